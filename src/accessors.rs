@@ -1,37 +1,53 @@
-use crate::{ fs, AccessorResult, FsEntryType };
+use crate::{ fs, AccessorResult, FileTimeStamp, FileTimeStampRaw, FsEntryType, NnPath, QueryEntryId };
 
 mod file;
 mod directory;
+mod guard;
 
-pub use file::{ FileAccessor, FAccessor };
-pub use directory::{DAccessor, DirectoryAccessor, DirectoryEntry, DirectoryEntryType };
+pub use file::{ FileAccessor, FAccessor, OperateRangeId, QueryRangeInfo };
+pub use directory::{DAccessor, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing };
+pub(crate) use directory::read_all_entries;
+pub use guard::set_abort_on_panic;
+
+use guard::{ guard, raw_path };
 
-use std::ffi::CStr;
 use skyline::{nn, println};
 
+/// Space reported by `FileSystemAccessor::get_free_space_size` and `get_total_space_size` for accessors without a quota.
+pub const UNLIMITED_SPACE_SIZE: usize = i64::MAX as usize;
+
+/// Run `f` on the path handed over by the SDK once it is validated, or report why it isn't valid.
+fn with_path<F: FnOnce(NnPath) -> AccessorResult>(path: *const u8, f: F) -> AccessorResult {
+    // SAFETY: The SDK always passes null-terminated paths
+    match unsafe { NnPath::from_ptr(path) } {
+        Ok(path) => f(path),
+        Err(e) => e,
+    }
+}
+
 #[repr(C)]
-struct FsAccessorVtable {
-    destructor: extern "C" fn (&mut FsAccessor),
-    deleter: extern "C" fn (&mut FsAccessor),
-    create_file: extern "C" fn (&mut FsAccessor, *const u8, usize, i32) -> AccessorResult,
-    delete_file: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
-    create_directory: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
-    delete_directory: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
-    delete_directory_recursively: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
-    clean_directory_recursively: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
-    rename_file: extern "C" fn (&mut FsAccessor, *const u8, *const u8) -> AccessorResult,
-    rename_directory: extern "C" fn (&mut FsAccessor, *const u8, *const u8) -> AccessorResult,
-    get_entry_type: extern "C" fn (&mut FsAccessor, &mut FsEntryType, *const u8) -> AccessorResult,
-    get_free_space_size: extern "C" fn (&mut FsAccessor, &mut usize, *const u8) -> AccessorResult,
-    get_total_space_size: extern "C" fn (&mut FsAccessor, &mut usize, *const u8) -> AccessorResult,
-    open_file: extern "C" fn (&mut FsAccessor, *mut *mut FAccessor, *const u8, nn::fs::OpenMode) -> AccessorResult, // *mut *mut is actually std::unique_ptr
-    open_directory: extern "C" fn (&mut FsAccessor, *mut *mut DAccessor, *const u8, nn::fs::OpenDirectoryMode) -> AccessorResult,
-    commit: extern "C" fn (&mut FsAccessor) -> AccessorResult,
-    commit_provisionally: extern "C" fn (&mut FsAccessor, u64) -> AccessorResult,
-    rollback: extern "C" fn (&mut FsAccessor) -> AccessorResult,
-    flush: extern "C" fn (&mut FsAccessor) -> AccessorResult,
-    get_file_time_stamp_raw: extern "C" fn (&mut FsAccessor, *mut u64, *const u8) -> AccessorResult, // takes *mut nn::fs::FileTimeStampRaw
-    query_entry: extern "C" fn (&mut FsAccessor,) -> AccessorResult // more args but idgaf 
+pub(crate) struct FsAccessorVtable {
+    pub(crate) destructor: extern "C" fn (&mut FsAccessor),
+    pub(crate) deleter: extern "C" fn (&mut FsAccessor),
+    pub(crate) create_file: extern "C" fn (&mut FsAccessor, *const u8, usize, i32) -> AccessorResult,
+    pub(crate) delete_file: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
+    pub(crate) create_directory: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
+    pub(crate) delete_directory: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
+    pub(crate) delete_directory_recursively: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
+    pub(crate) clean_directory_recursively: extern "C" fn (&mut FsAccessor, *const u8) -> AccessorResult,
+    pub(crate) rename_file: extern "C" fn (&mut FsAccessor, *const u8, *const u8) -> AccessorResult,
+    pub(crate) rename_directory: extern "C" fn (&mut FsAccessor, *const u8, *const u8) -> AccessorResult,
+    pub(crate) get_entry_type: extern "C" fn (&mut FsAccessor, &mut FsEntryType, *const u8) -> AccessorResult,
+    pub(crate) get_free_space_size: extern "C" fn (&mut FsAccessor, &mut usize, *const u8) -> AccessorResult,
+    pub(crate) get_total_space_size: extern "C" fn (&mut FsAccessor, &mut usize, *const u8) -> AccessorResult,
+    pub(crate) open_file: extern "C" fn (&mut FsAccessor, *mut *mut FAccessor, *const u8, nn::fs::OpenMode) -> AccessorResult, // *mut *mut is actually std::unique_ptr
+    pub(crate) open_directory: extern "C" fn (&mut FsAccessor, *mut *mut DAccessor, *const u8, nn::fs::OpenDirectoryMode) -> AccessorResult,
+    pub(crate) commit: extern "C" fn (&mut FsAccessor) -> AccessorResult,
+    pub(crate) commit_provisionally: extern "C" fn (&mut FsAccessor, u64) -> AccessorResult,
+    pub(crate) rollback: extern "C" fn (&mut FsAccessor) -> AccessorResult,
+    pub(crate) flush: extern "C" fn (&mut FsAccessor) -> AccessorResult,
+    pub(crate) get_file_time_stamp_raw: extern "C" fn (&mut FsAccessor, &mut FileTimeStampRaw, *const u8) -> AccessorResult,
+    pub(crate) query_entry: extern "C" fn (&mut FsAccessor, *mut u8, usize, *const u8, usize, u32, *const u8) -> AccessorResult
 }
 
 static FSACCESSOR_VTABLE: FsAccessorVtable  = FsAccessorVtable {
@@ -60,12 +76,12 @@ static FSACCESSOR_VTABLE: FsAccessorVtable  = FsAccessorVtable {
 
 #[repr(C)]
 pub struct FsAccessor {
-    vtable: &'static FsAccessorVtable,
+    pub(crate) vtable: &'static FsAccessorVtable,
     accessor: Box<dyn FileSystemAccessor>,
 }
 
 impl FsAccessor {
-    pub fn new<A: FileSystemAccessor + 'static>(accessor: A) -> *mut Self {
+    pub(crate) fn new<A: FileSystemAccessor + 'static>(accessor: A) -> *mut Self {
         let out = fs::detail::alloc::<Self>();
 
         // SAFETY: Do not change this way of assigning the values in case of refactoring. Dereferencing `out` to assign a new instance of the struct would call the destructor for the Box field, which is uninitialized, and cause a crash.
@@ -80,7 +96,12 @@ impl FsAccessor {
         out
     }
 
-    extern "C" fn destructor(&mut self) {}
+    extern "C" fn destructor(&mut self) {
+        guard("FsAccessor::destructor", "", || {
+            unsafe { std::ptr::drop_in_place(&mut self.accessor) };
+            AccessorResult::Success
+        });
+    }
 
     extern "C" fn deleter(&mut self) {
         self.destructor();
@@ -88,162 +109,351 @@ impl FsAccessor {
     }
 
     extern "C" fn get_entry_type(&mut self, entry_type: &mut FsEntryType, path: *const u8) -> AccessorResult {
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        match self.accessor.get_entry_type(&filepath.strip_prefix("/").unwrap()) {
-            Ok(result) => {
-                *entry_type = result;
-                AccessorResult::Success
-            },
-            Err(e) => e,
-        }
+        guard("FsAccessor::get_entry_type", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.get_entry_type(&filepath) {
+                Ok(result) => {
+                    *entry_type = result;
+                    AccessorResult::Success
+                },
+                Err(e) => e,
+            }
+        }))
     }
 
     extern "C" fn create_file(&mut self, path: *const u8, size: usize, mode: i32) -> AccessorResult {
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        self.accessor.create_file(&filepath, size)
+        guard("FsAccessor::create_file", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.check_free_space(&filepath, size) {
+                AccessorResult::Success => self.accessor.create_file(&filepath, size),
+                e => e,
+            }
+        }))
     }
     
-    extern "C" fn open_file(&mut self, file_accessor: *mut *mut FAccessor, path: *const u8, mode: nn::fs::OpenMode) -> AccessorResult { // unique_accessor is actually std::unique_ptr
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        match self.accessor.open_file(&filepath.strip_prefix("/").unwrap(), mode) {
-            Ok(mut accessor) => {
-                unsafe { *file_accessor = &mut *accessor };
-                AccessorResult::Success
-            },
-            Err(e) => e,
-        }
+    extern "C" fn open_file(&mut self, file_accessor: *mut *mut FAccessor, path: *const u8, mode: nn::fs::OpenMode) -> AccessorResult { // file_accessor is actually std::unique_ptr
+        guard("FsAccessor::open_file", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.open_file(&filepath, mode) {
+                Ok(accessor) => {
+                    // The SDK takes ownership and releases it through the deleter in the vtable
+                    unsafe { *file_accessor = FAccessor::new(accessor, filepath, mode) };
+                    AccessorResult::Success
+                },
+                Err(e) => e,
+            }
+        }))
     }
 
     extern "C" fn rename_file(&mut self, path: *const u8, new_path: *const u8) -> AccessorResult {
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-        let new_filepath: std::path::PathBuf = unsafe { CStr::from_ptr(new_path as _).to_str().unwrap().into() };
-
-        self.accessor.rename_file(&filepath, &new_filepath)
+        guard("FsAccessor::rename_file", &raw_path(path), || with_path(path, |filepath| with_path(new_path, |new_filepath| {
+            self.accessor.rename_file(&filepath, &new_filepath)
+        })))
     }
 
     extern "C" fn delete_file(&mut self, path: *const u8) -> AccessorResult {
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        self.accessor.delete_file(&filepath)
+        guard("FsAccessor::delete_file", &raw_path(path), || with_path(path, |filepath| {
+            self.accessor.delete_file(&filepath)
+        }))
     }
 
     extern "C" fn create_directory(&mut self, path: *const u8) -> AccessorResult {
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        self.accessor.delete_directory(&filepath)
+        guard("FsAccessor::create_directory", &raw_path(path), || with_path(path, |filepath| {
+            self.accessor.create_directory(&filepath)
+        }))
     }
 
-    extern "C" fn open_directory(&mut self, directory_accessor: *mut *mut DAccessor, path: *const u8, mode: nn::fs::OpenDirectoryMode) -> AccessorResult {
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        match self.accessor.open_directory(&filepath.strip_prefix("/").unwrap(), mode) {
-            Ok(mut accessor) => {
-                unsafe { *directory_accessor = &mut *accessor };
-                AccessorResult::Success
-            },
-            Err(e) => e,
-        }
+    extern "C" fn open_directory(&mut self, directory_accessor: *mut *mut DAccessor, path: *const u8, mode: nn::fs::OpenDirectoryMode) -> AccessorResult { // directory_accessor is actually std::unique_ptr
+        guard("FsAccessor::open_directory", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.open_directory(&filepath, mode) {
+                Ok(accessor) => {
+                    unsafe { *directory_accessor = DAccessor::new(accessor, filepath) };
+                    AccessorResult::Success
+                },
+                Err(e) => e,
+            }
+        }))
     }
 
     extern "C" fn rename_directory(&mut self, path: *const u8, new_path: *const u8) -> AccessorResult {
-
-        let dir_path: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-        let new_dirpath: std::path::PathBuf = unsafe { CStr::from_ptr(new_path as _).to_str().unwrap().into() };
-
-        self.accessor.rename_directory(&dir_path, &new_dirpath)
+        guard("FsAccessor::rename_directory", &raw_path(path), || with_path(path, |dir_path| with_path(new_path, |new_dirpath| {
+            self.accessor.rename_directory(&dir_path, &new_dirpath)
+        })))
     }
 
     extern "C" fn delete_directory(&mut self, path: *const u8) -> AccessorResult {
-
-        let filepath: std::path::PathBuf = unsafe { CStr::from_ptr(path as _).to_str().unwrap().into() };
-
-        self.accessor.delete_directory(&filepath)
+        guard("FsAccessor::delete_directory", &raw_path(path), || with_path(path, |filepath| {
+            self.accessor.delete_directory(&filepath)
+        }))
     }
 
     extern "C" fn delete_directory_recursively(&mut self, path: *const u8) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        guard("FsAccessor::delete_directory_recursively", &raw_path(path), || with_path(path, |dir_path| {
+            self.accessor.delete_directory_recursively(&dir_path)
+        }))
     }
 
     extern "C" fn clean_directory_recursively(&mut self, path: *const u8) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        guard("FsAccessor::clean_directory_recursively", &raw_path(path), || with_path(path, |dir_path| {
+            self.accessor.clean_directory_recursively(&dir_path)
+        }))
     }
 
     extern "C" fn get_free_space_size(&mut self, out_size: &mut usize, path: *const u8) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        guard("FsAccessor::get_free_space_size", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.get_free_space_size(&filepath) {
+                Ok(size) => {
+                    *out_size = size;
+                    AccessorResult::Success
+                },
+                Err(e) => e,
+            }
+        }))
     }
 
     extern "C" fn get_total_space_size(&mut self, out_size: &mut usize, path: *const u8) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        guard("FsAccessor::get_total_space_size", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.get_total_space_size(&filepath) {
+                Ok(size) => {
+                    *out_size = size;
+                    AccessorResult::Success
+                },
+                Err(e) => e,
+            }
+        }))
     }
 
     extern "C" fn commit(&mut self) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        guard("FsAccessor::commit", "", || {
+            self.accessor.commit()
+        })
     }
 
-    extern "C" fn commit_provisionally(&mut self, arg: u64) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+    extern "C" fn commit_provisionally(&mut self, counter: u64) -> AccessorResult {
+        guard("FsAccessor::commit_provisionally", "", || {
+            self.accessor.commit_provisionally(counter)
+        })
     }
 
     extern "C" fn rollback(&mut self) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        guard("FsAccessor::rollback", "", || {
+            self.accessor.rollback()
+        })
     }
 
     extern "C" fn flush(&mut self) -> AccessorResult {
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+        // Files are flushed through their own accessors, there is nothing to do for the filesystem as a whole
+        AccessorResult::Success
     }
 
-    extern "C" fn get_file_time_stamp_raw(&mut self, timestamp_out: *mut u64, path: *const u8) -> AccessorResult { // takes *mut nn::fs::FileTimeStampRaw
-        panic!("FsAccessor");
-
-        AccessorResult::Unimplemented
+    extern "C" fn get_file_time_stamp_raw(&mut self, timestamp_out: &mut FileTimeStampRaw, path: *const u8) -> AccessorResult {
+        guard("FsAccessor::get_file_time_stamp_raw", &raw_path(path), || with_path(path, |filepath| {
+            match self.accessor.get_file_time_stamp(&filepath) {
+                Ok(timestamp) => {
+                    *timestamp_out = timestamp.into();
+                    AccessorResult::Success
+                },
+                Err(e) => e,
+            }
+        }))
     }
-    
-    extern "C" fn query_entry(&mut self) -> AccessorResult { // more args but idgaf 
-        panic!("FsAccessor");
 
-        AccessorResult::Unimplemented
+    extern "C" fn query_entry(&mut self, out_buffer: *mut u8, out_buffer_len: usize, in_buffer: *const u8, in_buffer_len: usize, query_id: u32, path: *const u8) -> AccessorResult {
+        guard("FsAccessor::query_entry", &raw_path(path), || with_path(path, |filepath| {
+            let query_id = match QueryEntryId::from_raw(query_id) {
+                Some(query_id) => query_id,
+                None => return AccessorResult::Unsupported,
+            };
+
+            // The SDK passes null buffers for queries that don't take or return anything
+            let output: &mut [u8] = if out_buffer.is_null() {
+                &mut []
+            } else {
+                unsafe { std::slice::from_raw_parts_mut(out_buffer, out_buffer_len) }
+            };
+
+            let input: &[u8] = if in_buffer.is_null() {
+                &[]
+            } else {
+                unsafe { std::slice::from_raw_parts(in_buffer, in_buffer_len) }
+            };
+
+            self.accessor.query_entry(output, input, query_id, &filepath)
+        }))
     }
 }
 
 pub trait FileSystemAccessor {
-    fn get_entry_type(&self, path: &std::path::Path) -> Result<FsEntryType, AccessorResult>;
-    fn create_file(&self, path: &std::path::Path, size: usize) -> AccessorResult {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult>;
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
         AccessorResult::Unimplemented
     }
-    fn open_file(&self, path: &std::path::Path, mode: nn::fs::OpenMode) -> Result<*mut FAccessor, AccessorResult>;
-    fn rename_file(&self, path: &std::path::Path, new_path: &std::path::Path) -> AccessorResult {
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult>;
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
         AccessorResult::Unimplemented
     }
-    fn delete_file(&self, path: &std::path::Path) -> AccessorResult {
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
         AccessorResult::Unimplemented
     }
-    fn create_directory(&self, path: &std::path::Path) -> AccessorResult {
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
         AccessorResult::Unimplemented
     }
-    fn open_directory(&self, path: &std::path::Path, mode: nn::fs::OpenDirectoryMode) -> Result<*mut DAccessor, AccessorResult>;
-    fn rename_directory(&self, path: &std::path::Path, new_path: &std::path::Path) -> AccessorResult {
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult>;
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
         AccessorResult::Unimplemented
     }
-    fn delete_directory(&self, path: &std::path::Path) -> AccessorResult {
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
         AccessorResult::Unimplemented
     }
-}
\ No newline at end of file
+    /// Delete a directory along with everything it contains.
+    ///
+    /// The default implementation empties the directory with `clean_directory_recursively` and then removes it with `delete_directory`.
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        match self.clean_directory_recursively(path) {
+            AccessorResult::Success => self.delete_directory(path),
+            e => e,
+        }
+    }
+    /// Delete everything contained in a directory, leaving the directory itself in place.
+    ///
+    /// The default implementation walks the tree with `open_directory`, removing files with `delete_file` and subdirectories with `delete_directory_recursively`.
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        let entries = match self.open_directory(path, nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).and_then(|mut directory| read_all_entries(&mut *directory)) {
+            Ok(entries) => entries,
+            Err(e) => return e,
+        };
+
+        for entry in entries {
+            let entry_path = match entry.path.file_name().and_then(|name| name.to_str()).map(|name| path.join(name)) {
+                Some(Ok(entry_path)) => entry_path,
+                Some(Err(e)) => return e,
+                None => return AccessorResult::Unexpected,
+            };
+
+            let result = match entry.ty {
+                DirectoryEntryType::Directory => self.delete_directory_recursively(&entry_path),
+                DirectoryEntryType::File(_) => self.delete_file(&entry_path),
+            };
+
+            match result {
+                AccessorResult::Success => (),
+                e => return e,
+            }
+        }
+
+        AccessorResult::Success
+    }
+    /// Bytes that can still be written under `path`. Accessors without a quota report `UNLIMITED_SPACE_SIZE`.
+    fn get_free_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        Ok(UNLIMITED_SPACE_SIZE)
+    }
+    /// Total capacity of the storage backing `path`. Accessors without a quota report `UNLIMITED_SPACE_SIZE`.
+    fn get_total_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        Ok(UNLIMITED_SPACE_SIZE)
+    }
+    /// Creation, modification and access times of the file at `path`.
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        Err(AccessorResult::Unsupported)
+    }
+    /// Answer a `QueryEntry` request about `path`, writing the answer to `output`. Queries the accessor doesn't handle are declined with `AccessorResult::Unsupported`.
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        AccessorResult::Unsupported
+    }
+    /// Make every change since the last commit permanent. Accessors that write through immediately have nothing to do.
+    fn commit(&self) -> AccessorResult {
+        AccessorResult::Success
+    }
+    /// Commit the current changes provisionally, tagged with `counter`, so they can still be rolled back to.
+    fn commit_provisionally(&self, counter: u64) -> AccessorResult {
+        AccessorResult::Unsupported
+    }
+    /// Discard every change since the last commit.
+    fn rollback(&self) -> AccessorResult {
+        AccessorResult::Unsupported
+    }
+    /// Check that `size` more bytes fit under `path`, returning `AccessorResult::OutOfSpace` when they don't.
+    ///
+    /// Called before `create_file`. Accessors with a quota should also return `AccessorResult::OutOfSpace` from `FileAccessor::write` and `FileAccessor::set_size` when growing a file would exceed it.
+    fn check_free_space(&self, path: &NnPath, size: usize) -> AccessorResult {
+        match self.get_free_space_size(path) {
+            Ok(free_size) if free_size < size => AccessorResult::OutOfSpace,
+            Ok(_) => AccessorResult::Success,
+            Err(e) => e,
+        }
+    }
+}
+#[cfg(all(test, feature = "host"))]
+mod tests {
+    use super::*;
+    use crate::{ mount, PATH_LENGTH_MAX };
+
+    struct PanickingFile;
+
+    impl FileAccessor for PanickingFile {
+        fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+            panic!("read");
+        }
+
+        fn get_size(&mut self) -> Result<usize, AccessorResult> {
+            Ok(0)
+        }
+    }
+
+    /// Panics on everything but opening files, which panic on read instead.
+    struct PanickingFileSystem;
+
+    impl FileSystemAccessor for PanickingFileSystem {
+        fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+            panic!("get_entry_type");
+        }
+
+        fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+            panic!("create_file");
+        }
+
+        fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+            Ok(Box::new(PanickingFile))
+        }
+
+        fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+            panic!("open_directory");
+        }
+
+        fn commit(&self) -> AccessorResult {
+            panic!("commit");
+        }
+    }
+
+    #[test]
+    fn panics_are_reported_as_errors() {
+        let handle = mount("panic", PanickingFileSystem).unwrap();
+
+        assert_eq!(fs::host::get_entry_type("panic:/file").err(), Some(AccessorResult::Unexpected));
+        assert_eq!(fs::host::create_file("panic:/file", 0).err(), Some(AccessorResult::Unexpected));
+        assert_eq!(fs::host::open_directory("panic:/", nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).err(), Some(AccessorResult::Unexpected));
+        assert_eq!(fs::host::commit("panic").err(), Some(AccessorResult::Unexpected));
+
+        let mut file = fs::host::open_file("panic:/file", nn::fs::OpenMode_OpenMode_Read).unwrap();
+        assert_eq!(file.read(0, &mut [0; 4]).err(), Some(AccessorResult::Unexpected));
+        assert_eq!(file.get_size(), Ok(0));
+        drop(file);
+
+        handle.unmount();
+    }
+
+    #[test]
+    fn invalid_paths_are_rejected_before_the_accessor() {
+        let handle = mount("paths", PanickingFileSystem).unwrap();
+        let long_path = ["paths:/", &"a".repeat(PATH_LENGTH_MAX + 1)].concat();
+
+        assert_eq!(fs::host::get_entry_type("paths:/../file").err(), Some(AccessorResult::PathNotFound));
+        assert_eq!(fs::host::get_entry_type(&long_path).err(), Some(AccessorResult::PathNotFound));
+
+        handle.unmount();
+    }
+
+    #[test]
+    fn flush_succeeds_without_reaching_the_accessor() {
+        let accessor = FsAccessor::new(PanickingFileSystem);
+
+        assert_eq!(unsafe { ((*accessor).vtable.flush)(&mut *accessor) }, AccessorResult::Success);
+        unsafe { ((*accessor).vtable.deleter)(&mut *accessor) };
+    }
+}
@@ -1,17 +1,18 @@
 use std::path::PathBuf;
-use std::io::Write;
 
-use crate::{ fs, AccessorResult };
+use crate::{ fs, AccessorResult, FileTimeStamp, NnPath };
 
 use skyline::nn;
 
+use super::guard::guard;
+
 #[repr(C)]
-struct DirectoryAccessorVtable {
+pub(crate) struct DirectoryAccessorVtable {
     // also type info at VTable - 0x8
-    destructor: extern "C" fn(&mut DAccessor),
-    deleter: extern "C" fn(&mut DAccessor),
-    read: extern "C" fn(&mut DAccessor, &mut isize, *mut nn::fs::DirectoryEntry, usize) -> AccessorResult,
-    get_entry_count: extern "C" fn(&mut DAccessor, &mut isize) -> AccessorResult
+    pub(crate) destructor: extern "C" fn(&mut DAccessor),
+    pub(crate) deleter: extern "C" fn(&mut DAccessor),
+    pub(crate) read: extern "C" fn(&mut DAccessor, &mut isize, *mut nn::fs::DirectoryEntry, usize) -> AccessorResult,
+    pub(crate) get_entry_count: extern "C" fn(&mut DAccessor, &mut isize) -> AccessorResult
 }
 
 static DACCESSOR_VTABLE: DirectoryAccessorVtable = DirectoryAccessorVtable {
@@ -23,8 +24,9 @@ static DACCESSOR_VTABLE: DirectoryAccessorVtable = DirectoryAccessorVtable {
 
 #[repr(C)]
 pub struct DAccessor {
-    vtable: &'static DirectoryAccessorVtable,
+    pub(crate) vtable: &'static DirectoryAccessorVtable,
     accessor: Box<dyn DirectoryAccessor>,
+    path: NnPath,
 }
 
 #[derive(Copy, Clone)]
@@ -36,28 +38,33 @@ pub enum DirectoryEntryType {
 #[derive(Clone)]
 pub struct DirectoryEntry {
     pub path: PathBuf,
-    pub ty: DirectoryEntryType
+    pub ty: DirectoryEntryType,
+    /// Timestamps of the entry, for backends that can provide them
+    pub timestamp: Option<FileTimeStamp>,
 }
 
 impl DirectoryEntry {
     pub fn new() -> Self {
         DirectoryEntry {
             path: PathBuf::new(),
-            ty: DirectoryEntryType::Directory
+            ty: DirectoryEntryType::Directory,
+            timestamp: None,
         }
     }
 }
 
 
 impl DAccessor {
-    pub fn new<D: DirectoryAccessor + 'static>(accessor: D) -> *mut Self {
+    /// Allocate an accessor through `fs::detail::alloc` so ownership can be handed to the SDK.
+    pub(crate) fn new(accessor: Box<dyn DirectoryAccessor>, path: NnPath) -> *mut Self {
         let mut out = fs::detail::alloc::<Self>();
 
         // SAFETY: Do not change this way of assigning the values in case of refactoring. Dereferencing `out` to assign a new instance of the struct would call the destructor for the Box field, which is uninitialized, and cause a crash.
         unsafe {
             out.write(Self {
                 vtable: &DACCESSOR_VTABLE,
-                accessor: Box::new(accessor) as _,
+                accessor,
+                path,
             });
         }
 
@@ -65,7 +72,16 @@ impl DAccessor {
         out
     }
 
-    extern "C" fn destructor(&mut self) { }
+    extern "C" fn destructor(&mut self) {
+        let Self { accessor, path, .. } = self;
+
+        guard("DAccessor::destructor", path.as_str(), || {
+            unsafe { std::ptr::drop_in_place(accessor) };
+            AccessorResult::Success
+        });
+
+        unsafe { std::ptr::drop_in_place(path) };
+    }
 
     extern "C" fn deleter(&mut self) {
         self.destructor();
@@ -73,45 +89,54 @@ impl DAccessor {
     }
 
     extern "C" fn read(&mut self, out_count: &mut isize, buffer: *mut nn::fs::DirectoryEntry, buffer_len: usize) -> AccessorResult {
-        let mut buf = vec![DirectoryEntry::new(); buffer_len];
-        let mut buffer = unsafe {
-            std::slice::from_raw_parts_mut(buffer, buffer_len)
-        };
-        match self.accessor.read(buf.as_mut_slice()) {
-            Ok(size) => {
-                for (idx, entry) in buf.iter().enumerate() {
-                    let mut char_buffer = &mut buffer[idx].name[..];
-                    char_buffer.fill(0);
-                    let string = entry.path
-                        .file_name()
-                        .unwrap()
-                        .to_str()
-                        .unwrap();
-                    char_buffer.write(string.as_bytes());
-                    char_buffer[string.len()] = 0;
-                    match entry.ty {
-                        DirectoryEntryType::Directory => buffer[idx].type_ = 0,
-                        DirectoryEntryType::File(size) => {
-                            buffer[idx].type_ = 1;
-                            buffer[idx].fileSize = size;
+        let Self { accessor, path, .. } = self;
+
+        guard("DAccessor::read", path.as_str(), || {
+            let mut buf = vec![DirectoryEntry::new(); buffer_len];
+            let buffer = unsafe {
+                std::slice::from_raw_parts_mut(buffer, buffer_len)
+            };
+            match accessor.read(buf.as_mut_slice()) {
+                Ok(size) => {
+                    for (idx, entry) in buf[..size].iter().enumerate() {
+                        let name = entry.path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+
+                        // Names too long for the SDK are cut on a character boundary, leaving room for the terminator
+                        let mut len = name.len().min(buffer[idx].name.len() - 1);
+                        while !name.is_char_boundary(len) {
+                            len -= 1;
+                        }
+
+                        buffer[idx].name.fill(0);
+                        buffer[idx].name[..len].copy_from_slice(&name.as_bytes()[..len]);
+                        match entry.ty {
+                            DirectoryEntryType::Directory => buffer[idx].type_ = 0,
+                            DirectoryEntryType::File(size) => {
+                                buffer[idx].type_ = 1;
+                                buffer[idx].fileSize = size;
+                            }
                         }
                     }
-                }
-                *out_count = buf.len() as isize;
-            },
-            Err(e) => return e,
-        }
-        AccessorResult::Success
+                    *out_count = size as isize;
+                },
+                Err(e) => return e,
+            }
+            AccessorResult::Success
+        })
     }
 
     extern "C" fn get_entry_count(&mut self, out_count: &mut isize) -> AccessorResult {
-        match self.accessor.get_entry_count() {
-            Ok(size) => {
-                *out_count = size as isize;
-                AccessorResult::Success
-            },
-            Err(e) => e
-        }
+        let Self { accessor, path, .. } = self;
+
+        guard("DAccessor::get_entry_count", path.as_str(), || {
+            match accessor.get_entry_count() {
+                Ok(size) => {
+                    *out_count = size as isize;
+                    AccessorResult::Success
+                },
+                Err(e) => e
+            }
+        })
     }
 }
 
@@ -119,4 +144,52 @@ pub trait DirectoryAccessor {
     fn read(&mut self, buffer: &mut [DirectoryEntry]) -> Result<usize, AccessorResult>;
 
     fn get_entry_count(&mut self) -> Result<usize, AccessorResult>;
+}
+
+/// Read every entry of a freshly opened directory.
+pub(crate) fn read_all_entries(accessor: &mut dyn DirectoryAccessor) -> Result<Vec<DirectoryEntry>, AccessorResult> {
+    let mut entries = vec![DirectoryEntry::new(); accessor.get_entry_count()?];
+    let size = accessor.read(entries.as_mut_slice())?;
+
+    entries.truncate(size);
+    Ok(entries)
+}
+
+/// A `DirectoryAccessor` serving a list of entries gathered ahead of time, filtered by the `OpenDirectoryMode` it was opened with.
+pub struct DirectoryListing {
+    entries: Vec<DirectoryEntry>,
+    position: usize,
+}
+
+impl DirectoryListing {
+    pub fn new(entries: Vec<DirectoryEntry>, mode: nn::fs::OpenDirectoryMode) -> Self {
+        let include_directories = mode as u32 & nn::fs::OpenDirectoryMode_OpenDirectoryMode_Directory as u32 != 0;
+        let include_files = mode as u32 & nn::fs::OpenDirectoryMode_OpenDirectoryMode_File as u32 != 0;
+
+        let entries = entries.into_iter().filter(|entry| match entry.ty {
+            DirectoryEntryType::Directory => include_directories,
+            DirectoryEntryType::File(_) => include_files,
+        }).collect();
+
+        Self {
+            entries,
+            position: 0,
+        }
+    }
+}
+
+impl DirectoryAccessor for DirectoryListing {
+    fn read(&mut self, buffer: &mut [DirectoryEntry]) -> Result<usize, AccessorResult> {
+        let remaining = &self.entries[self.position..];
+        let count = remaining.len().min(buffer.len());
+
+        buffer[..count].clone_from_slice(&remaining[..count]);
+        self.position += count;
+
+        Ok(count)
+    }
+
+    fn get_entry_count(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.entries.len())
+    }
 }
\ No newline at end of file
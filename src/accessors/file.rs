@@ -1,18 +1,62 @@
-use crate::{ fs, AccessorResult };
+use std::convert::TryFrom;
 
-use skyline::nn;
+use crate::{ fs, AccessorResult, NnPath };
 
+use skyline::{nn, println};
+
+use super::guard::guard;
+
+#[repr(C)]
+pub(crate) struct FileAccessorVtable {
+    pub(crate) destructor: extern "C" fn(&mut FAccessor),
+    pub(crate) deleter: extern "C" fn(&mut FAccessor),
+    pub(crate) read: extern "C" fn(&mut FAccessor, &mut usize, usize, *mut u8, usize, u32) -> AccessorResult,
+    pub(crate) write: extern "C" fn(&mut FAccessor, usize, *const u8, usize, &nn::fs::WriteOption) -> AccessorResult,
+    pub(crate) flush: extern "C" fn(&mut FAccessor) -> AccessorResult,
+    pub(crate) set_size: extern "C" fn(&mut FAccessor, usize) -> AccessorResult,
+    pub(crate) get_size: extern "C" fn(&mut FAccessor, &mut usize) -> AccessorResult,
+    pub(crate) operate_range: extern "C" fn(&mut FAccessor, *mut u8, usize, u32, i64, i64, *const u8, usize) -> AccessorResult
+}
+
+/// Operations that can be requested on a range of a file through `nn::fs::OperateRange`.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum OperateRangeId {
+    FillZero = 0,
+    DestroySignature = 1,
+    Invalidate = 2,
+    QueryRange = 3,
+}
+
+impl OperateRangeId {
+    pub fn from_raw(operation_id: u32) -> Option<Self> {
+        match operation_id {
+            0 => Some(OperateRangeId::FillZero),
+            1 => Some(OperateRangeId::DestroySignature),
+            2 => Some(OperateRangeId::Invalidate),
+            3 => Some(OperateRangeId::QueryRange),
+            _ => None,
+        }
+    }
+}
+
+// Layout of nn::fs::QueryRangeInfo
 #[repr(C)]
-struct FileAccessorVtable {
-    destructor: extern "C" fn(&mut FAccessor),
-    deleter: extern "C" fn(&mut FAccessor),
-    read: extern "C" fn(&mut FAccessor, &mut usize, usize, *mut u8, usize, u32) -> AccessorResult,
-    write: extern "C" fn(&mut FAccessor, usize, *const u8, usize, &nn::fs::WriteOption) -> AccessorResult,
-    flush: extern "C" fn(&mut FAccessor) -> AccessorResult,
-    set_size: extern "C" fn(&mut FAccessor, usize) -> AccessorResult,
-    get_size: extern "C" fn(&mut FAccessor, &mut usize) -> AccessorResult,
-    // more here but no clue what they are
-    operate_range: extern "C" fn(&mut FAccessor, ) -> AccessorResult
+#[derive(Copy, Clone)]
+pub struct QueryRangeInfo {
+    pub aes_ctr_key_type_flag: i32,
+    pub speed_emulation_type_flag: i32,
+    pub reserved: [u8; 0x38],
+}
+
+impl Default for QueryRangeInfo {
+    fn default() -> Self {
+        Self {
+            aes_ctr_key_type_flag: 0,
+            speed_emulation_type_flag: 0,
+            reserved: [0; 0x38],
+        }
+    }
 }
 
 static FACCESSOR_VTABLE: FileAccessorVtable  = FileAccessorVtable {
@@ -28,13 +72,15 @@ static FACCESSOR_VTABLE: FileAccessorVtable  = FileAccessorVtable {
 
 #[repr(C)]
 pub struct FAccessor {
-    vtable: &'static FileAccessorVtable,
+    pub(crate) vtable: &'static FileAccessorVtable,
     options: nn::fs::OpenMode,
     accessor: Box<dyn FileAccessor>,
+    path: NnPath,
 }
 
 impl FAccessor {
-    pub fn new<F: FileAccessor + 'static>(accessor: F, options: nn::fs::OpenMode) -> *mut Self {
+    /// Allocate an accessor through `fs::detail::alloc` so ownership can be handed to the SDK.
+    pub(crate) fn new(accessor: Box<dyn FileAccessor>, path: NnPath, options: nn::fs::OpenMode) -> *mut Self {
         let mut out = fs::detail::alloc::<Self>();
 
         // SAFETY: Do not change this way of assigning the values in case of refactoring. Dereferencing `out` to assign a new instance of the struct would call the destructor for the Box field, which is uninitialized, and cause a crash.
@@ -42,7 +88,8 @@ impl FAccessor {
             out.write(Self {
                 vtable: &FACCESSOR_VTABLE,
                 options,
-                accessor: Box::new(accessor) as _,
+                accessor,
+                path,
             });
         }
 
@@ -50,7 +97,16 @@ impl FAccessor {
         out
     }
 
-    extern "C" fn destructor(&mut self) { }
+    extern "C" fn destructor(&mut self) {
+        let Self { accessor, path, .. } = self;
+
+        guard("FAccessor::destructor", path.as_str(), || {
+            unsafe { std::ptr::drop_in_place(accessor) };
+            AccessorResult::Success
+        });
+
+        unsafe { std::ptr::drop_in_place(path) };
+    }
 
     extern "C" fn deleter(&mut self) {
         self.destructor();
@@ -58,62 +114,110 @@ impl FAccessor {
     }
 
     extern "C" fn read(&mut self, read_size: &mut usize, offset: usize, buffer: *mut u8, buffer_len: usize, read_options: u32) -> AccessorResult {
-        println!("FAccessor::read");
-        let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, buffer_len) };
-        
-        match self.accessor.read(buffer, offset) {
-            Ok(size) => {
-                *read_size = size;
-                AccessorResult::Success
-            },
-            Err(e) => e
-        }
+        let Self { accessor, path, .. } = self;
+
+        guard("FAccessor::read", path.as_str(), || {
+            println!("FAccessor::read");
+            let buffer = unsafe { std::slice::from_raw_parts_mut(buffer, buffer_len) };
+
+            match accessor.read(buffer, offset) {
+                Ok(size) => {
+                    *read_size = size;
+                    AccessorResult::Success
+                },
+                Err(e) => e
+            }
+        })
     }
 
     extern "C" fn write(&mut self, offset: usize, data: *const u8, data_len: usize, write_options: &nn::fs::WriteOption) -> AccessorResult {
-        println!("FAccessor::write");
+        let Self { accessor, path, .. } = self;
 
-        let data = unsafe {
-            std::slice::from_raw_parts(data, data_len)
-        };
+        guard("FAccessor::write", path.as_str(), || {
+            println!("FAccessor::write");
 
-        match self.accessor.write(data, offset, true) {
-            Ok(_) => AccessorResult::Success,
-            Err(e) => e
-        }
+            let data = unsafe {
+                std::slice::from_raw_parts(data, data_len)
+            };
+
+            match accessor.write(data, offset, true) {
+                Ok(_) => AccessorResult::Success,
+                Err(e) => e
+            }
+        })
     }
 
     extern "C" fn flush(&mut self) -> AccessorResult {
-        println!("FAccessor::flush");
+        let Self { accessor, path, .. } = self;
+
+        guard("FAccessor::flush", path.as_str(), || {
+            println!("FAccessor::flush");
 
-        self.accessor.flush()
+            accessor.flush()
+        })
     }
 
     extern "C" fn set_size(&mut self, new_size: usize) -> AccessorResult {
-        println!("FAccessor::set_size");
+        let Self { accessor, path, .. } = self;
 
-        match self.accessor.set_size(new_size) {
-            Ok(()) => AccessorResult::Success,
-            Err(e) => e
-        }
+        guard("FAccessor::set_size", path.as_str(), || {
+            println!("FAccessor::set_size");
+
+            match accessor.set_size(new_size) {
+                Ok(()) => AccessorResult::Success,
+                Err(e) => e
+            }
+        })
     }
 
     extern "C" fn get_size(&mut self, out_size: &mut usize) -> AccessorResult {
-        println!("FAccessor::get_size");
-
-        match unsafe { (*self.accessor).get_size() } {
-            Ok(size) => {
-                *out_size = size;
-                AccessorResult::Success
-            },
-            Err(e) => e
-        }
+        let Self { accessor, path, .. } = self;
+
+        guard("FAccessor::get_size", path.as_str(), || {
+            println!("FAccessor::get_size");
+
+            match accessor.get_size() {
+                Ok(size) => {
+                    *out_size = size;
+                    AccessorResult::Success
+                },
+                Err(e) => e
+            }
+        })
     }
 
-    extern "C" fn operate_range(&mut self, /* ... */) -> AccessorResult {
-        panic!("FAccessor");
+    extern "C" fn operate_range(&mut self, out_buffer: *mut u8, out_buffer_len: usize, operation_id: u32, offset: i64, size: i64, in_buffer: *const u8, in_buffer_len: usize) -> AccessorResult {
+        let Self { accessor, path, .. } = self;
 
-        AccessorResult::Unsupported
+        guard("FAccessor::operate_range", path.as_str(), || {
+            let operation = match OperateRangeId::from_raw(operation_id) {
+                Some(operation) => operation,
+                None => return AccessorResult::Unsupported,
+            };
+
+            if operation == OperateRangeId::QueryRange && (out_buffer.is_null() || out_buffer_len < std::mem::size_of::<QueryRangeInfo>()) {
+                return AccessorResult::Unexpected;
+            }
+
+            // The SDK passes signed values, a negative range can't be meant for any file
+            let (offset, size) = match (usize::try_from(offset), usize::try_from(size)) {
+                (Ok(offset), Ok(size)) => (offset, size),
+                _ => return AccessorResult::Unexpected,
+            };
+
+            let mut info = QueryRangeInfo::default();
+
+            match accessor.operate_range(operation, offset, size, &mut info) {
+                AccessorResult::Success => {
+                    if operation == OperateRangeId::QueryRange {
+                        unsafe { (out_buffer as *mut QueryRangeInfo).write_unaligned(info) };
+                    }
+
+                    AccessorResult::Success
+                },
+                e => e
+            }
+        })
     }
 }
 
@@ -133,4 +237,35 @@ pub trait FileAccessor {
     fn flush(&mut self) -> AccessorResult {
         AccessorResult::Unsupported
     }
-}
\ No newline at end of file
+
+    /// Perform `operation` on `size` bytes starting at `offset`. `OperateRangeId::QueryRange` fills `info`, other operations leave it untouched.
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        AccessorResult::Unsupported
+    }
+}
+/// Lets wrappers such as `backends::DecompressingFile` hold the boxed files returned by `FileSystemAccessor::open_file`.
+impl<F: FileAccessor + ?Sized> FileAccessor for Box<F> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        (**self).read(buffer, offset)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        (**self).write(data, offset, should_append)
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        (**self).set_size(new_size)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        (**self).get_size()
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        (**self).flush()
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        (**self).operate_range(operation, offset, size, info)
+    }
+}
@@ -0,0 +1,64 @@
+use std::borrow::Cow;
+use std::ffi::CStr;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::AccessorResult;
+
+use skyline::println;
+
+static ABORT_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Abort the process when an accessor panics instead of reporting `AccessorResult::Unexpected` to the SDK.
+///
+/// Meant for debugging, so the crash report points at the panic instead of wherever the game trips over the error.
+pub fn set_abort_on_panic(abort: bool) {
+    ABORT_ON_PANIC.store(abort, Ordering::Relaxed);
+}
+
+/// Path handed over by the SDK, for logging purposes only.
+pub(crate) fn raw_path<'a>(path: *const u8) -> Cow<'a, str> {
+    if path.is_null() {
+        Cow::Borrowed("")
+    } else {
+        unsafe { CStr::from_ptr(path as _) }.to_string_lossy()
+    }
+}
+
+/// Run a vtable entry, making sure a panic never unwinds into the SDK.
+///
+/// The panic is logged along with the operation and path, then reported as `AccessorResult::Unexpected`.
+pub(crate) fn guard<F: FnOnce() -> AccessorResult>(operation: &str, path: &str, f: F) -> AccessorResult {
+    let payload = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => return result,
+        Err(payload) => payload,
+    };
+
+    let message = payload.downcast_ref::<&str>().copied()
+        .or_else(|| payload.downcast_ref::<String>().map(|message| message.as_str()))
+        .unwrap_or("Box<dyn Any>");
+
+    println!("[nn-fuse] {} panicked on '{}': {}", operation, path, message);
+
+    if ABORT_ON_PANIC.load(Ordering::Relaxed) {
+        std::process::abort();
+    }
+
+    AccessorResult::Unexpected
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passes_results_through() {
+        assert_eq!(guard("test", "", || AccessorResult::PathNotFound), AccessorResult::PathNotFound);
+    }
+
+    #[test]
+    fn reports_panics_as_unexpected() {
+        assert_eq!(guard("test", "file", || panic!("str payload")), AccessorResult::Unexpected);
+        assert_eq!(guard("test", "file", || panic!("{} payload", "String")), AccessorResult::Unexpected);
+    }
+}
@@ -0,0 +1,187 @@
+use skyline::nn;
+
+use crate::{ AccessorResult, FileAccessor };
+
+mod journal;
+pub use journal::JournalingFileSystem;
+
+mod memory;
+pub use memory::MemoryFileSystem;
+
+mod host_directory;
+pub use host_directory::{ HostDirectoryFileSystem, HostFile };
+
+mod overlay;
+pub use overlay::OverlayFileSystem;
+
+mod copy_on_write;
+pub use copy_on_write::CopyOnWriteFileSystem;
+
+mod compression;
+pub use compression::Compression;
+
+mod decompress;
+pub use decompress::{ DecompressingFile, DecompressingFileSystem };
+
+#[cfg(feature = "zstd")]
+mod seekable_zstd;
+#[cfg(feature = "zstd")]
+pub use seekable_zstd::{ compress_seekable_zstd, SeekableZstdFile };
+
+mod archive_file;
+use archive_file::ArchiveFile;
+
+mod archive_index;
+use archive_index::ArchiveIndex;
+
+mod sarc;
+pub use sarc::{ Endianness, SarcFileSystem };
+
+mod romfs;
+pub use romfs::RomFsFileSystem;
+
+mod partition;
+pub use partition::{ PartitionFileSystem, PartitionFormat };
+
+mod cache;
+pub use cache::CachingFileSystem;
+
+mod read_ahead;
+pub use read_ahead::ReadAheadFileSystem;
+
+mod tar;
+pub use tar::TarFileSystem;
+
+#[cfg(feature = "zip")]
+mod zip;
+#[cfg(feature = "zip")]
+pub use zip::ZipFileSystem;
+
+fn to_result(result: AccessorResult) -> Result<(), AccessorResult> {
+    match result {
+        AccessorResult::Success => Ok(()),
+        e => Err(e),
+    }
+}
+
+fn into_result(result: Result<(), AccessorResult>) -> AccessorResult {
+    match result {
+        Ok(()) => AccessorResult::Success,
+        Err(e) => e,
+    }
+}
+
+fn is_writable(mode: nn::fs::OpenMode) -> bool {
+    mode as u32 & (nn::fs::OpenMode_OpenMode_Write as u32 | nn::fs::OpenMode_OpenMode_Append as u32) != 0
+}
+
+/// Check that a file opened with `mode` allows `required`, failing with `AccessorResult::Unsupported` like the SDK does otherwise.
+///
+/// Backends holding their own files restrict them the same way the console does: reads need `OpenMode_Read`, writes and resizes need `OpenMode_Write`,
+/// and writes reaching past the end of the file also need `OpenMode_Append`.
+fn expect_mode(mode: nn::fs::OpenMode, required: nn::fs::OpenMode) -> Result<(), AccessorResult> {
+    if mode as u32 & required as u32 != 0 {
+        Ok(())
+    } else {
+        Err(AccessorResult::Unsupported)
+    }
+}
+
+fn read_to_end(file: &mut dyn FileAccessor) -> Result<Vec<u8>, AccessorResult> {
+    let mut data = vec![0; file.get_size()?];
+
+    read_exact(file, &mut data, 0)?;
+    Ok(data)
+}
+
+/// Fill `buffer` from `offset`, failing if the file ends before that.
+fn read_exact(file: &mut dyn FileAccessor, buffer: &mut [u8], offset: usize) -> Result<(), AccessorResult> {
+    let mut size = 0;
+
+    while size < buffer.len() {
+        match file.read(&mut buffer[size..], offset + size)? {
+            0 => return Err(AccessorResult::Unexpected),
+            read => size += read,
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+const READ: nn::fs::OpenMode = nn::fs::OpenMode_OpenMode_Read;
+#[cfg(test)]
+const WRITE: nn::fs::OpenMode = nn::fs::OpenMode_OpenMode_Write;
+#[cfg(test)]
+const APPEND: nn::fs::OpenMode = nn::fs::OpenMode_OpenMode_Append;
+
+#[cfg(test)]
+fn path(path: &str) -> crate::NnPath {
+    crate::NnPath::new(path).unwrap()
+}
+
+/// Up to `size` bytes read from `offset` in a single call.
+#[cfg(test)]
+fn read_at(file: &mut dyn FileAccessor, offset: usize, size: usize) -> Vec<u8> {
+    let mut buffer = vec![0; size];
+    let read = file.read(&mut buffer, offset).unwrap();
+
+    buffer.truncate(read);
+    buffer
+}
+
+/// Overwrite the file at `path` with `data` from `offset`, for changing what is under a wrapper without it noticing.
+#[cfg(test)]
+fn overwrite(fs: &dyn crate::FileSystemAccessor, path: &str, offset: usize, data: &[u8]) {
+    fs.open_file(&self::path(path), WRITE).unwrap().write(data, offset, false).unwrap();
+}
+
+/// A memory filesystem holding `files`, along with the directories leading to them.
+#[cfg(test)]
+fn memory_with(files: &[(&str, &[u8])]) -> MemoryFileSystem {
+    use crate::{ FileSystemAccessor, NnPath };
+
+    let fs = MemoryFileSystem::new();
+
+    for (path, data) in files {
+        let path = NnPath::new(path).unwrap();
+
+        for ancestor in path.ancestors().skip(1).collect::<Vec<_>>().into_iter().rev() {
+            fs.create_directory(&ancestor);
+        }
+
+        assert_eq!(fs.create_file(&path, 0), AccessorResult::Success);
+        fs.open_file(&path, WRITE | APPEND).unwrap().write(data, 0, true).unwrap();
+    }
+
+    fs
+}
+
+/// A read-only file holding `data`, for backends reading from a `FileAccessor`.
+#[cfg(test)]
+fn memory_file(data: &[u8]) -> Box<dyn FileAccessor> {
+    use crate::FileSystemAccessor;
+
+    memory_with(&[("file", data)]).open_file(&path("file"), READ).unwrap()
+}
+
+/// Contents of the file at `path`, read through `FileSystemAccessor::open_file`.
+#[cfg(test)]
+fn read_path(fs: &dyn crate::FileSystemAccessor, path: &str) -> Result<Vec<u8>, AccessorResult> {
+    read_to_end(&mut *fs.open_file(&crate::NnPath::new(path)?, READ)?)
+}
+
+/// Names and sizes of the entries of the directory at `path`, sizes being `None` for directories.
+#[cfg(test)]
+fn list_path(fs: &dyn crate::FileSystemAccessor, path: &str) -> Result<Vec<(String, Option<i64>)>, AccessorResult> {
+    let mut directory = fs.open_directory(&crate::NnPath::new(path)?, nn::fs::OpenDirectoryMode_OpenDirectoryMode_All)?;
+
+    Ok(crate::accessors::read_all_entries(&mut *directory)?.into_iter().map(|entry| {
+        let size = match entry.ty {
+            crate::DirectoryEntryType::File(size) => Some(size),
+            crate::DirectoryEntryType::Directory => None,
+        };
+
+        (entry.path.to_string_lossy().into_owned(), size)
+    }).collect())
+}
@@ -0,0 +1,38 @@
+use std::sync::{Arc, Mutex};
+
+use super::read_exact;
+use crate::{AccessorResult, FileAccessor};
+
+/// A file stored uncompressed as a contiguous range of an archive.
+pub(crate) struct ArchiveFile<F: FileAccessor> {
+    archive: Arc<Mutex<F>>,
+    offset: usize,
+    size: usize,
+}
+
+impl<F: FileAccessor> ArchiveFile<F> {
+    pub(crate) fn new(archive: Arc<Mutex<F>>, offset: usize, size: usize) -> Self {
+        Self {
+            archive,
+            offset,
+            size,
+        }
+    }
+}
+
+impl<F: FileAccessor> FileAccessor for ArchiveFile<F> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        if offset >= self.size {
+            return Ok(0);
+        }
+
+        let size = buffer.len().min(self.size - offset);
+
+        read_exact(&mut *self.archive.lock().unwrap(), &mut buffer[..size], self.offset + offset)?;
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.size)
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+
+use crate::{AccessorResult, DirectoryEntry, DirectoryEntryType, FileTimeStamp, FsEntryType, NnPath};
+
+enum Node<T> {
+    File(T),
+    Directory(BTreeSet<String>),
+}
+
+/// Directory tree of an archive that lists its files by full path, built once when it is opened.
+pub(crate) struct ArchiveIndex<T> {
+    entries: BTreeMap<NnPath, Node<T>>,
+}
+
+impl<T> ArchiveIndex<T> {
+    pub(crate) fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(NnPath::root(), Node::Directory(BTreeSet::new()));
+
+        Self {
+            entries,
+        }
+    }
+
+    /// Add `path` to the listing of its parent directory.
+    fn link(&mut self, path: &NnPath) -> Result<(), AccessorResult> {
+        match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => match self.entries.get_mut(&parent) {
+                Some(Node::Directory(children)) => {
+                    children.insert(name.to_owned());
+                    Ok(())
+                },
+                _ => Err(AccessorResult::Unexpected),
+            },
+            _ => Ok(()),
+        }
+    }
+
+    /// Create `path` and its missing ancestors. Archives don't always have records for their directories.
+    pub(crate) fn insert_directory(&mut self, path: &NnPath) -> Result<(), AccessorResult> {
+        let missing: Vec<NnPath> = path.ancestors().take_while(|ancestor| !self.entries.contains_key(ancestor)).collect();
+
+        for directory in missing.into_iter().rev() {
+            self.entries.insert(directory.clone(), Node::Directory(BTreeSet::new()));
+            self.link(&directory)?;
+        }
+
+        match self.entries.get(path) {
+            Some(Node::Directory(_)) => Ok(()),
+            _ => Err(AccessorResult::Unexpected),
+        }
+    }
+
+    /// Add a file along with its missing parent directories, replacing any previous file with the same path.
+    pub(crate) fn insert_file(&mut self, path: NnPath, file: T) -> Result<(), AccessorResult> {
+        let parent = path.parent().ok_or(AccessorResult::Unexpected)?;
+
+        self.insert_directory(&parent)?;
+
+        if let Some(Node::Directory(_)) = self.entries.get(&path) {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        self.link(&path)?;
+        self.entries.insert(path, Node::File(file));
+        Ok(())
+    }
+
+    pub(crate) fn entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        match self.entries.get(path) {
+            Some(Node::File(_)) => Ok(FsEntryType::File),
+            Some(Node::Directory(_)) => Ok(FsEntryType::Directory),
+            None => Err(AccessorResult::PathNotFound),
+        }
+    }
+
+    pub(crate) fn file(&self, path: &NnPath) -> Result<&T, AccessorResult> {
+        match self.entries.get(path) {
+            Some(Node::File(file)) => Ok(file),
+            _ => Err(AccessorResult::PathNotFound),
+        }
+    }
+
+    /// List the directory at `path`, `describe` giving the size and timestamps of each file.
+    pub(crate) fn list(&self, path: &NnPath, describe: impl Fn(&T) -> (usize, Option<FileTimeStamp>)) -> Result<Vec<DirectoryEntry>, AccessorResult> {
+        let children = match self.entries.get(path) {
+            Some(Node::Directory(children)) => children,
+            _ => return Err(AccessorResult::PathNotFound),
+        };
+
+        let mut entries = Vec::with_capacity(children.len());
+
+        for name in children {
+            let (ty, timestamp) = match self.entries.get(&path.join(name)?) {
+                Some(Node::File(file)) => {
+                    let (size, timestamp) = describe(file);
+                    (DirectoryEntryType::File(size as i64), timestamp)
+                },
+                _ => (DirectoryEntryType::Directory, None),
+            };
+
+            entries.push(DirectoryEntry {
+                path: PathBuf::from(name),
+                ty,
+                timestamp,
+            });
+        }
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,464 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use crate::{AccessorResult, DirectoryAccessor, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, OperateRangeId, QueryEntryId, QueryRangeInfo};
+
+const DEFAULT_BLOCK_SIZE: usize = 0x4000;
+
+type BlockKey = (NnPath, usize);
+
+/// Blocks of every file opened through a `CachingFileSystem`, evicted least recently used first.
+struct BlockCache {
+    block_size: usize,
+    budget: usize,
+    /// Total size of the cached blocks
+    size: usize,
+    /// Bumped by every invalidation, so blocks read from the backend while it happened aren't cached
+    generation: u64,
+    tick: u64,
+    blocks: BTreeMap<BlockKey, (u64, Arc<[u8]>)>,
+    /// Blocks by the tick they were last used at
+    recency: BTreeMap<u64, BlockKey>,
+}
+
+impl BlockCache {
+    fn get(&mut self, key: &BlockKey) -> Option<Arc<[u8]>> {
+        let (last_used, data) = self.blocks.get_mut(key)?;
+
+        self.tick += 1;
+        self.recency.remove(last_used);
+        self.recency.insert(self.tick, key.clone());
+        *last_used = self.tick;
+
+        Some(data.clone())
+    }
+
+    fn insert(&mut self, key: BlockKey, data: Arc<[u8]>) {
+        if data.len() > self.budget {
+            return;
+        }
+
+        while self.size + data.len() > self.budget {
+            let oldest = *self.recency.keys().next().unwrap();
+            let key = self.recency.remove(&oldest).unwrap();
+
+            self.size -= self.blocks.remove(&key).unwrap().1.len();
+        }
+
+        self.tick += 1;
+        self.size += data.len();
+        self.recency.insert(self.tick, key.clone());
+
+        if let Some((last_used, previous)) = self.blocks.insert(key, (self.tick, data)) {
+            self.recency.remove(&last_used);
+            self.size -= previous.len();
+        }
+    }
+
+    /// Drop the blocks of every file whose path matches `filter`.
+    fn invalidate(&mut self, filter: impl Fn(&NnPath) -> bool) {
+        let stale: Vec<BlockKey> = self.blocks.keys().filter(|(path, _)| filter(path)).cloned().collect();
+
+        for key in stale {
+            let (last_used, data) = self.blocks.remove(&key).unwrap();
+
+            self.recency.remove(&last_used);
+            self.size -= data.len();
+        }
+
+        self.generation += 1;
+    }
+}
+
+/// Wraps a `FileSystemAccessor` and caches what is read from its files in fixed-size blocks, so small repeated reads are served from memory.
+///
+/// The cache is shared by every file opened through the wrapper and kept under a memory budget, dropping the least recently used blocks first.
+/// Writes, resizes and `OperateRangeId::Invalidate` made through the wrapper drop the blocks of the file, changes made to the wrapped filesystem directly aren't noticed.
+/// Reads at least as large as the budget bypass the cache.
+pub struct CachingFileSystem<A: FileSystemAccessor> {
+    inner: A,
+    cache: Arc<Mutex<BlockCache>>,
+}
+
+impl<A: FileSystemAccessor> CachingFileSystem<A> {
+    /// Cache up to `budget` bytes of 16 KiB blocks.
+    pub fn new(inner: A, budget: usize) -> Self {
+        Self::with_block_size(inner, DEFAULT_BLOCK_SIZE, budget)
+    }
+
+    pub fn with_block_size(inner: A, block_size: usize, budget: usize) -> Self {
+        Self {
+            inner,
+            cache: Arc::new(Mutex::new(BlockCache {
+                block_size: block_size.max(1),
+                budget,
+                size: 0,
+                generation: 0,
+                tick: 0,
+                blocks: BTreeMap::new(),
+                recency: BTreeMap::new(),
+            })),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Total size of the blocks currently cached.
+    pub fn cached_size(&self) -> usize {
+        self.cache.lock().unwrap().size
+    }
+
+    /// Drop every cached block.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().invalidate(|_| true);
+    }
+
+    /// Drop the blocks of the files matching `filter` once a mutation of the wrapped filesystem is done, passing its result through.
+    fn invalidate_after(&self, result: AccessorResult, filter: impl Fn(&NnPath) -> bool) -> AccessorResult {
+        self.cache.lock().unwrap().invalidate(filter);
+        result
+    }
+}
+
+impl<A: FileSystemAccessor> FileSystemAccessor for CachingFileSystem<A> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.inner.get_entry_type(path)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.invalidate_after(self.inner.create_file(path, size), |file| file == path)
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        Ok(Box::new(CachedFile {
+            file: self.inner.open_file(path, mode)?,
+            path: path.clone(),
+            mode,
+            cache: self.cache.clone(),
+        }))
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.invalidate_after(self.inner.rename_file(path, new_path), |file| file == path || file == new_path)
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        self.invalidate_after(self.inner.delete_file(path), |file| file == path)
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        self.inner.create_directory(path)
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        self.inner.open_directory(path, mode)
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.invalidate_after(self.inner.rename_directory(path, new_path), |file| file.starts_with(path) || file.starts_with(new_path))
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        self.inner.delete_directory(path)
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.invalidate_after(self.inner.delete_directory_recursively(path), |file| file.starts_with(path))
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.invalidate_after(self.inner.clean_directory_recursively(path), |file| file.starts_with(path))
+    }
+
+    fn get_free_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.inner.get_free_space_size(path)
+    }
+
+    fn get_total_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.inner.get_total_space_size(path)
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        self.inner.get_file_time_stamp(path)
+    }
+
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        self.inner.query_entry(output, input, query_id, path)
+    }
+
+    fn commit(&self) -> AccessorResult {
+        self.inner.commit()
+    }
+
+    fn commit_provisionally(&self, counter: u64) -> AccessorResult {
+        self.inner.commit_provisionally(counter)
+    }
+
+    fn rollback(&self) -> AccessorResult {
+        // Any file can go back to an earlier state
+        self.invalidate_after(self.inner.rollback(), |_| true)
+    }
+
+    fn check_free_space(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.inner.check_free_space(path, size)
+    }
+}
+
+/// A file opened through a `CachingFileSystem`.
+struct CachedFile {
+    file: Box<dyn FileAccessor>,
+    path: NnPath,
+    mode: nn::fs::OpenMode,
+    cache: Arc<Mutex<BlockCache>>,
+}
+
+impl CachedFile {
+    fn invalidate(&self) {
+        let path = &self.path;
+
+        self.cache.lock().unwrap().invalidate(|file| file == path);
+    }
+
+    /// Block `index` of the file, shorter than the block size if the file ends within it.
+    fn block(&mut self, index: usize, block_size: usize) -> Result<Arc<[u8]>, AccessorResult> {
+        let key = (self.path.clone(), index);
+        let generation = {
+            let mut cache = self.cache.lock().unwrap();
+
+            if let Some(data) = cache.get(&key) {
+                return Ok(data);
+            }
+
+            cache.generation
+        };
+
+        // The cache isn't locked while reading from the backend, so other files can still be served from it
+        let mut data = vec![0; block_size];
+        let mut size = 0;
+
+        while size < block_size {
+            match self.file.read(&mut data[size..], index * block_size + size)? {
+                0 => break,
+                read => size += read,
+            }
+        }
+
+        data.truncate(size);
+
+        let data: Arc<[u8]> = data.into();
+        let mut cache = self.cache.lock().unwrap();
+
+        if cache.generation == generation {
+            cache.insert(key, data.clone());
+        }
+
+        Ok(data)
+    }
+}
+
+impl FileAccessor for CachedFile {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        let (block_size, budget) = {
+            let cache = self.cache.lock().unwrap();
+            (cache.block_size, cache.budget)
+        };
+
+        // Handles that can't read go to the wrapped file, so it refuses them instead of the cache answering for it
+        if buffer.len() >= budget || self.mode as u32 & nn::fs::OpenMode_OpenMode_Read as u32 == 0 {
+            return self.file.read(buffer, offset);
+        }
+
+        let mut size = 0;
+
+        while size < buffer.len() {
+            let position = offset + size;
+            let block = self.block(position / block_size, block_size)?;
+            let start = position % block_size;
+
+            if start >= block.len() {
+                break;
+            }
+
+            let length = (buffer.len() - size).min(block.len() - start);
+
+            buffer[size..size + length].copy_from_slice(&block[start..start + length]);
+            size += length;
+
+            // A short block is the end of the file
+            if block.len() < block_size && start + length == block.len() {
+                break;
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        let result = self.file.write(data, offset, should_append);
+
+        self.invalidate();
+        result
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        let result = self.file.set_size(new_size);
+
+        self.invalidate();
+        result
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        self.file.get_size()
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        self.file.flush()
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        if operation == OperateRangeId::Invalidate {
+            self.invalidate();
+        }
+
+        self.file.operate_range(operation, offset, size, info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ memory_with, overwrite, path, read_at, read_path, READ, WRITE };
+
+    #[test]
+    fn repeated_reads_are_served_from_memory() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("file", &[1; 40])]), 16, 1024);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+
+        assert_eq!(read_at(&mut *file, 10, 10), vec![1; 10]);
+        assert_eq!(fs.cached_size(), 32);
+
+        overwrite(fs.inner(), "file", 0, &[2; 40]);
+        assert_eq!(read_at(&mut *file, 0, 40), [vec![1; 32], vec![2; 8]].concat());
+        assert_eq!(fs.cached_size(), 40);
+
+        fs.clear();
+        assert_eq!(fs.cached_size(), 0);
+        assert_eq!(read_at(&mut *file, 0, 40), vec![2; 40]);
+    }
+
+    #[test]
+    fn least_recently_used_blocks_are_evicted() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("file", &[1; 64])]), 16, 48);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+
+        for block in 0..3 {
+            read_at(&mut *file, block * 16, 1);
+        }
+
+        // Block 0 becomes more recent than block 1, which is dropped to make room for block 3
+        read_at(&mut *file, 0, 1);
+        read_at(&mut *file, 48, 1);
+        assert_eq!(fs.cached_size(), 48);
+
+        overwrite(fs.inner(), "file", 0, &[2; 64]);
+        assert_eq!(read_at(&mut *file, 0, 1), [1]);
+        assert_eq!(read_at(&mut *file, 32, 1), [1]);
+        assert_eq!(read_at(&mut *file, 48, 1), [1]);
+        assert_eq!(read_at(&mut *file, 16, 1), [2]);
+        assert!(fs.cached_size() <= 48);
+    }
+
+    #[test]
+    fn files_share_the_budget() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("a", &[1; 16]), ("b", &[2; 16]), ("c", &[3; 16])]), 16, 32);
+        let mut files: Vec<_> = ["a", "b", "c"].iter().map(|name| fs.open_file(&path(name), READ).unwrap()).collect();
+
+        for file in &mut files {
+            read_at(&mut **file, 0, 8);
+        }
+
+        assert_eq!(fs.cached_size(), 32);
+
+        // The block of the first file was the one dropped
+        for (name, data) in &[("a", [4; 16]), ("b", [5; 16]), ("c", [6; 16])] {
+            overwrite(fs.inner(), name, 0, data);
+        }
+
+        assert_eq!(read_at(&mut *files[2], 0, 8), vec![3; 8]);
+        assert_eq!(read_at(&mut *files[1], 0, 8), vec![2; 8]);
+        assert_eq!(read_at(&mut *files[0], 0, 8), vec![4; 8]);
+    }
+
+    #[test]
+    fn large_reads_bypass_the_cache() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("file", &[1; 64])]), 16, 32);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+
+        assert_eq!(read_at(&mut *file, 0, 64), vec![1; 64]);
+        assert_eq!(read_at(&mut *file, 8, 32), vec![1; 32]);
+        assert_eq!(fs.cached_size(), 0);
+    }
+
+    #[test]
+    fn reads_stop_at_the_end_of_the_file() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("file", &[1; 20])]), 16, 1024);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+
+        assert_eq!(read_at(&mut *file, 10, 30), vec![1; 10]);
+        assert_eq!(read_at(&mut *file, 20, 5), Vec::<u8>::new());
+        assert_eq!(read_at(&mut *file, 100, 5), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn changes_made_through_the_cache_invalidate_it() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("file", &[1; 32]), ("other", &[5; 8])]), 16, 1024);
+        let mut reader = fs.open_file(&path("file"), READ).unwrap();
+        let mut writer = fs.open_file(&path("file"), READ | WRITE).unwrap();
+
+        assert_eq!(read_at(&mut *reader, 0, 32), vec![1; 32]);
+        assert_eq!(read_path(&fs, "other"), Ok(vec![5; 8]));
+
+        writer.write(&[2; 4], 4, false).unwrap();
+        assert_eq!(read_at(&mut *reader, 0, 8), [1, 1, 1, 1, 2, 2, 2, 2]);
+
+        writer.set_size(6).unwrap();
+        assert_eq!(read_at(&mut *reader, 0, 32), [1, 1, 1, 1, 2, 2]);
+
+        overwrite(fs.inner(), "file", 0, &[3; 6]);
+        assert_eq!(reader.operate_range(OperateRangeId::Invalidate, 0, 6, &mut QueryRangeInfo::default()), AccessorResult::Success);
+        assert_eq!(read_at(&mut *reader, 0, 32), vec![3; 6]);
+
+        // Only the blocks of the file are dropped
+        overwrite(fs.inner(), "other", 0, &[6; 8]);
+        assert_eq!(read_path(&fs, "other"), Ok(vec![5; 8]));
+    }
+
+    #[test]
+    fn write_only_handles_cant_read_cached_blocks() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("file", &[1; 32])]), 16, 1024);
+        let mut writer = fs.open_file(&path("file"), WRITE).unwrap();
+
+        assert_eq!(read_path(&fs, "file"), Ok(vec![1; 32]));
+        assert_eq!(fs.cached_size(), 32);
+        assert_eq!(writer.read(&mut [0; 8], 0), Err(AccessorResult::Unsupported));
+    }
+
+    #[test]
+    fn renames_and_deletes_invalidate_the_paths() {
+        let fs = CachingFileSystem::with_block_size(memory_with(&[("dir/a", b"aaaa"), ("b", b"bbbb")]), 16, 1024);
+
+        assert_eq!(read_path(&fs, "dir/a"), Ok(b"aaaa".to_vec()));
+        assert_eq!(read_path(&fs, "b"), Ok(b"bbbb".to_vec()));
+
+        assert_eq!(fs.delete_file(&path("b")), AccessorResult::Success);
+        assert_eq!(fs.rename_directory(&path("dir"), &path("moved")), AccessorResult::Success);
+        assert_eq!(fs.rename_file(&path("moved/a"), &path("b")), AccessorResult::Success);
+        assert_eq!(fs.cached_size(), 0);
+
+        assert_eq!(read_path(&fs, "b"), Ok(b"aaaa".to_vec()));
+    }
+}
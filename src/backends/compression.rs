@@ -0,0 +1,259 @@
+use std::convert::TryInto;
+
+use crate::AccessorResult;
+
+const YAZ0_MAGIC: &[u8] = b"Yaz0";
+const YAZ0_HEADER_SIZE: usize = 0x10;
+
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+
+/// Length of the longest header `Compression::decompressed_size` looks at, a zstd frame header with every optional field
+pub(crate) const SIZE_HEADER_LENGTH: usize = 18;
+
+/// Compression formats archives and files commonly come wrapped in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    Yaz0,
+    /// Only supported with the `zstd` feature
+    Zstd,
+    /// LZ4 frame format, only supported with the `lz4` feature
+    Lz4,
+}
+
+impl Compression {
+    /// Identify the compression `data` is wrapped in from its magic.
+    pub(crate) fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(YAZ0_MAGIC) {
+            Some(Compression::Yaz0)
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Some(Compression::Zstd)
+        } else if data.starts_with(LZ4_MAGIC) {
+            Some(Compression::Lz4)
+        } else {
+            None
+        }
+    }
+
+    /// Identify the compression of a file from the extension of its name.
+    pub(crate) fn from_extension(name: &str) -> Option<Self> {
+        match name.rsplit_once('.')?.1.to_ascii_lowercase().as_str() {
+            "yaz0" | "szs" => Some(Compression::Yaz0),
+            "zs" | "zst" | "zstd" => Some(Compression::Zstd),
+            "lz4" => Some(Compression::Lz4),
+            _ => None,
+        }
+    }
+
+    /// Decompressed size recorded in the header `header` starts with, if the format and the compressor put it there.
+    ///
+    /// Only the first zstd or LZ4 frame is looked at, so streams made of several frames can decompress to more.
+    pub(crate) fn decompressed_size(self, header: &[u8]) -> Option<usize> {
+        match self {
+            Compression::Yaz0 => Some(u32::from_be_bytes(header.get(4..8)?.try_into().unwrap()) as usize),
+            Compression::Zstd => {
+                let descriptor = *header.get(4)?;
+                let single_segment = descriptor & 0x20 != 0;
+                let dictionary_id_size = [0, 1, 2, 4][(descriptor & 0x3) as usize];
+
+                let content_size_size = match descriptor >> 6 {
+                    0 if single_segment => 1,
+                    0 => return None,
+                    1 => 2,
+                    2 => 4,
+                    _ => 8,
+                };
+
+                // The window descriptor is left out of single segment frames
+                let start = 5 + !single_segment as usize + dictionary_id_size;
+                let size = header.get(start..start + content_size_size)?.iter().rev().fold(0, |size, &byte| size << 8 | byte as usize);
+
+                // Two byte sizes are stored minus 256
+                Some(if content_size_size == 2 { size + 256 } else { size })
+            },
+            Compression::Lz4 => {
+                // Set in the frame descriptor when the content size follows the block descriptor
+                if header.get(4)? & 0x8 == 0 {
+                    return None;
+                }
+
+                Some(u64::from_le_bytes(header.get(6..14)?.try_into().unwrap()) as usize)
+            },
+        }
+    }
+
+    pub(crate) fn decompress(self, data: &[u8]) -> Result<Vec<u8>, AccessorResult> {
+        match self {
+            Compression::Yaz0 => decompress_yaz0(data),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => decompress_zstd(data),
+            #[cfg(not(feature = "zstd"))]
+            Compression::Zstd => Err(AccessorResult::Unsupported),
+            #[cfg(feature = "lz4")]
+            Compression::Lz4 => decompress_lz4(data),
+            #[cfg(not(feature = "lz4"))]
+            Compression::Lz4 => Err(AccessorResult::Unsupported),
+        }
+    }
+}
+
+/// Decompress data wrapped in `data` if it is compressed, or return it as is.
+pub(crate) fn decompress_if_needed(data: Vec<u8>) -> Result<Vec<u8>, AccessorResult> {
+    match Compression::detect(&data) {
+        Some(compression) => compression.decompress(&data),
+        None => Ok(data),
+    }
+}
+
+pub(crate) fn decompress_yaz0(data: &[u8]) -> Result<Vec<u8>, AccessorResult> {
+    if data.len() < YAZ0_HEADER_SIZE || !data.starts_with(YAZ0_MAGIC) {
+        return Err(AccessorResult::Unexpected);
+    }
+
+    let size = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+    // The size comes from the header, only reserve what the input could plausibly expand to and let longer outputs grow
+    let mut out = Vec::with_capacity(size.min(data.len() * 9));
+    let mut input = data[YAZ0_HEADER_SIZE..].iter().copied();
+    let mut next = || input.next().ok_or(AccessorResult::Unexpected);
+
+    while out.len() < size {
+        let group = next()?;
+
+        // Each bit of the group header tells whether the next chunk is a literal byte or a back-reference, starting from the highest bit
+        for bit in (0..8).rev() {
+            if out.len() >= size {
+                break;
+            }
+
+            if group & (1 << bit) != 0 {
+                out.push(next()?);
+                continue;
+            }
+
+            let (first, second) = (next()?, next()?);
+            let distance = ((first as usize & 0xf) << 8 | second as usize) + 1;
+            let length = match first >> 4 {
+                0 => next()? as usize + 0x12,
+                length => length as usize + 2,
+            };
+
+            let start = out.len().checked_sub(distance).ok_or(AccessorResult::Unexpected)?;
+
+            // The source and destination can overlap, so this has to go one byte at a time
+            for idx in start..start + length {
+                out.push(out[idx]);
+            }
+        }
+    }
+
+    out.truncate(size);
+    Ok(out)
+}
+
+/// Size of the skippable frame `input` starts with, including its header. zstd and LZ4 share the same skippable frame format.
+#[cfg(any(feature = "zstd", feature = "lz4"))]
+fn skippable_frame_size(input: &[u8]) -> Option<usize> {
+    if input.len() >= 8 && u32::from_le_bytes(input[..4].try_into().unwrap()) & 0xffff_fff0 == 0x184d_2a50 {
+        Some(8 + u32::from_le_bytes(input[4..8].try_into().unwrap()) as usize)
+    } else {
+        None
+    }
+}
+
+/// Decompress every frame of a zstd stream, skipping skippable frames.
+#[cfg(feature = "zstd")]
+pub(crate) fn decompress_zstd(data: &[u8]) -> Result<Vec<u8>, AccessorResult> {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+    let mut input = data;
+
+    while !input.is_empty() {
+        if let Some(size) = skippable_frame_size(input) {
+            input = input.get(size..).ok_or(AccessorResult::Unexpected)?;
+            continue;
+        }
+
+        let mut decoder = ruzstd::decoding::StreamingDecoder::new(&mut input).map_err(|_| AccessorResult::Unexpected)?;
+        decoder.read_to_end(&mut out).map_err(|_| AccessorResult::Unexpected)?;
+    }
+
+    Ok(out)
+}
+
+/// Decompress every frame of an LZ4 frame stream, skipping skippable frames.
+#[cfg(feature = "lz4")]
+pub(crate) fn decompress_lz4(data: &[u8]) -> Result<Vec<u8>, AccessorResult> {
+    use std::io::Read;
+
+    let mut out = Vec::new();
+    let mut input = data;
+
+    while !input.is_empty() {
+        if let Some(size) = skippable_frame_size(input) {
+            input = input.get(size..).ok_or(AccessorResult::Unexpected)?;
+            continue;
+        }
+
+        // The decoder stops at the end of the frame, leaving the following ones in `input`
+        let mut decoder = lz4_flex::frame::FrameDecoder::new(input);
+        decoder.read_to_end(&mut out).map_err(|_| AccessorResult::Unexpected)?;
+        input = decoder.into_inner();
+    }
+
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn yaz0_back_references() {
+        let mut data = b"Yaz0".to_vec();
+        data.extend_from_slice(&33u32.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+
+        // Three literals, a two byte reference copying 9 bytes, then a three byte one copying 0x12 + 3 bytes
+        data.extend_from_slice(&[0b1110_0000, b'a', b'b', b'c', 0x70, 0x02, 0x00, 0x02, 0x03]);
+
+        assert_eq!(decompress_yaz0(&data), Ok(b"abc".repeat(11)));
+        assert_eq!(Compression::detect(&data).unwrap().decompressed_size(&data), Some(33));
+
+        assert!(decompress_yaz0(&data[..data.len() - 1]).is_err());
+        assert!(decompress_yaz0(&data[..8]).is_err());
+    }
+
+    #[test]
+    fn yaz0_sizes_beyond_the_input_fail() {
+        let mut data = b"Yaz0".to_vec();
+        data.extend_from_slice(&u32::MAX.to_be_bytes());
+        data.extend_from_slice(&[0; 8]);
+        data.extend_from_slice(&[0b1000_0000, b'a']);
+
+        assert_eq!(decompress_yaz0(&data), Err(AccessorResult::Unexpected));
+    }
+
+    #[test]
+    fn zstd_content_sizes() {
+        let header = |descriptor: u8, fields: &[u8]| [ZSTD_MAGIC, &[descriptor], fields].concat();
+
+        // Single segment with a one byte size, no window descriptor
+        assert_eq!(Compression::Zstd.decompressed_size(&header(0x20, &[200])), Some(200));
+        // Two byte size after the window descriptor
+        assert_eq!(Compression::Zstd.decompressed_size(&header(0x40, &[0x58, 0x00, 0x01])), Some(0x100 + 256));
+        // Four byte size after a one byte dictionary ID
+        assert_eq!(Compression::Zstd.decompressed_size(&header(0x81, &[0x58, 0x7, 0x78, 0x56, 0x34, 0x12])), Some(0x1234_5678));
+        // No size
+        assert_eq!(Compression::Zstd.decompressed_size(&header(0x00, &[0x58])), None);
+        assert_eq!(Compression::Zstd.decompressed_size(&header(0xc0, &[0x58, 0, 0])), None);
+    }
+
+    #[test]
+    fn lz4_content_sizes() {
+        let header = |flags: u8| [LZ4_MAGIC, &[flags, 0x40], &1234u64.to_le_bytes()[..], &[0]].concat();
+
+        assert_eq!(Compression::Lz4.decompressed_size(&header(0x68)), Some(1234));
+        assert_eq!(Compression::Lz4.decompressed_size(&header(0x60)), None);
+    }
+}
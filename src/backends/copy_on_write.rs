@@ -0,0 +1,460 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use skyline::nn;
+
+use super::{ into_result, is_writable, read_to_end, to_result, MemoryFileSystem };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, QueryEntryId};
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Layer {
+    Upper,
+    Base,
+}
+
+/// Presents a writable view of a read-only `FileSystemAccessor` without ever writing to it.
+///
+/// Creations, writes and renames go to the upper layer, a `MemoryFileSystem` unless another one is provided, files being copied up the first time they are opened for writing.
+/// Deleting an entry of the base filesystem records a whiteout, which hides it and everything under it from then on.
+///
+/// `commit`, `commit_provisionally` and `rollback` are forwarded to the upper layer, the whiteouts going back along with it when it supports rolling back, as a `JournalingFileSystem` does.
+pub struct CopyOnWriteFileSystem<B: FileSystemAccessor, U: FileSystemAccessor = MemoryFileSystem> {
+    base: B,
+    upper: U,
+    whiteouts: Mutex<BTreeSet<NnPath>>,
+    /// Whiteouts as of the last commit, and of the last provisional commit since then
+    committed_whiteouts: Mutex<(BTreeSet<NnPath>, Option<BTreeSet<NnPath>>)>,
+}
+
+impl<B: FileSystemAccessor> CopyOnWriteFileSystem<B> {
+    pub fn new(base: B) -> Self {
+        Self::with_upper(base, MemoryFileSystem::new())
+    }
+}
+
+impl<B: FileSystemAccessor, U: FileSystemAccessor> CopyOnWriteFileSystem<B, U> {
+    pub fn with_upper(base: B, upper: U) -> Self {
+        Self {
+            base,
+            upper,
+            whiteouts: Mutex::new(BTreeSet::new()),
+            committed_whiteouts: Mutex::new((BTreeSet::new(), None)),
+        }
+    }
+
+    pub fn base(&self) -> &B {
+        &self.base
+    }
+
+    /// The layer holding every change made so far.
+    pub fn upper(&self) -> &U {
+        &self.upper
+    }
+
+    fn is_base_visible(whiteouts: &BTreeSet<NnPath>, path: &NnPath) -> bool {
+        !path.ancestors().any(|ancestor| whiteouts.contains(&ancestor))
+    }
+
+    fn base_entry_type(&self, whiteouts: &BTreeSet<NnPath>, path: &NnPath) -> Option<FsEntryType> {
+        if Self::is_base_visible(whiteouts, path) {
+            self.base.get_entry_type(path).ok()
+        } else {
+            None
+        }
+    }
+
+    fn entry_type(&self, whiteouts: &BTreeSet<NnPath>, path: &NnPath) -> Result<(FsEntryType, Layer), AccessorResult> {
+        match self.upper.get_entry_type(path) {
+            Ok(ty) => Ok((ty, Layer::Upper)),
+            Err(_) => self.base_entry_type(whiteouts, path).map(|ty| (ty, Layer::Base)).ok_or(AccessorResult::PathNotFound),
+        }
+    }
+
+    fn expect_type(&self, whiteouts: &BTreeSet<NnPath>, path: &NnPath, ty: FsEntryType) -> Result<Layer, AccessorResult> {
+        match self.entry_type(whiteouts, path)? {
+            (entry_type, layer) if entry_type == ty => Ok(layer),
+            _ => Err(AccessorResult::PathNotFound),
+        }
+    }
+
+    /// Check that `path` doesn't exist yet but its parent directory does.
+    fn expect_vacant(&self, whiteouts: &BTreeSet<NnPath>, path: &NnPath) -> Result<(), AccessorResult> {
+        let parent = path.parent().ok_or(AccessorResult::PathAlreadyExists)?;
+
+        self.expect_type(whiteouts, &parent, FsEntryType::Directory)?;
+
+        match self.entry_type(whiteouts, path) {
+            Ok(_) => Err(AccessorResult::PathAlreadyExists),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Create the directories leading to `path` in the upper layer.
+    fn ensure_upper_directory(&self, path: &NnPath) -> Result<(), AccessorResult> {
+        let mut missing: Vec<NnPath> = path.ancestors().take_while(|ancestor| self.upper.get_entry_type(ancestor).is_err()).collect();
+
+        while let Some(directory) = missing.pop() {
+            to_result(self.upper.create_directory(&directory))?;
+        }
+
+        Ok(())
+    }
+
+    fn copy_up(&self, path: &NnPath, new_path: &NnPath) -> Result<(), AccessorResult> {
+        let data = read_to_end(&mut *self.base.open_file(path, nn::fs::OpenMode_OpenMode_Read)?)?;
+
+        self.ensure_upper_directory(&new_path.parent().unwrap_or_default())?;
+        to_result(self.upper.create_file(new_path, data.len()))?;
+
+        self.upper.open_file(new_path, nn::fs::OpenMode_OpenMode_Write | nn::fs::OpenMode_OpenMode_Append)?.write(&data, 0, true)
+    }
+
+    /// Remove `path` from the upper layer and hide it in the base filesystem.
+    fn remove(&self, whiteouts: &mut BTreeSet<NnPath>, path: &NnPath) -> Result<(), AccessorResult> {
+        match self.upper.get_entry_type(path) {
+            Ok(FsEntryType::File) => to_result(self.upper.delete_file(path))?,
+            Ok(FsEntryType::Directory) => to_result(self.upper.delete_directory_recursively(path))?,
+            Err(_) => (),
+        }
+
+        if self.base_entry_type(whiteouts, path).is_some() {
+            whiteouts.retain(|whiteout| !whiteout.starts_with(path));
+            whiteouts.insert(path.clone());
+        }
+
+        Ok(())
+    }
+
+    fn list(&self, whiteouts: &BTreeSet<NnPath>, path: &NnPath) -> Result<Vec<DirectoryEntry>, AccessorResult> {
+        let mut entries = BTreeMap::new();
+        let layers = [(&self.base as &dyn FileSystemAccessor, Layer::Base), (&self.upper as &dyn FileSystemAccessor, Layer::Upper)];
+
+        for (fs, layer) in layers.iter() {
+            let is_directory = match layer {
+                Layer::Base => self.base_entry_type(whiteouts, path) == Some(FsEntryType::Directory),
+                Layer::Upper => self.upper.get_entry_type(path) == Ok(FsEntryType::Directory),
+            };
+
+            if !is_directory {
+                continue;
+            }
+
+            let mut directory = fs.open_directory(path, nn::fs::OpenDirectoryMode_OpenDirectoryMode_All)?;
+
+            for entry in crate::accessors::read_all_entries(&mut *directory)? {
+                if let Some(name) = entry.path.file_name().and_then(|name| name.to_str()) {
+                    if *layer == Layer::Upper || !whiteouts.contains(&path.join(name)?) {
+                        entries.insert(name.to_owned(), (entry.ty, entry.timestamp));
+                    }
+                }
+            }
+        }
+
+        Ok(entries.into_iter().map(|(name, (ty, timestamp))| DirectoryEntry { path: PathBuf::from(name), ty, timestamp }).collect())
+    }
+
+    fn copy_directory(&self, whiteouts: &BTreeSet<NnPath>, path: &NnPath, new_path: &NnPath) -> Result<(), AccessorResult> {
+        self.ensure_upper_directory(new_path)?;
+
+        for entry in self.list(whiteouts, path)? {
+            let name = entry.path.to_str().ok_or(AccessorResult::Unexpected)?;
+            let (entry_path, new_entry_path) = (path.join(name)?, new_path.join(name)?);
+
+            match (entry.ty, self.upper.get_entry_type(&entry_path).is_ok()) {
+                (DirectoryEntryType::Directory, _) => self.copy_directory(whiteouts, &entry_path, &new_entry_path)?,
+                (DirectoryEntryType::File(_), true) => to_result(self.upper.rename_file(&entry_path, &new_entry_path))?,
+                (DirectoryEntryType::File(_), false) => self.copy_up(&entry_path, &new_entry_path)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<B: FileSystemAccessor, U: FileSystemAccessor> FileSystemAccessor for CopyOnWriteFileSystem<B, U> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        self.entry_type(&whiteouts, path).map(|(ty, _)| ty)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result((|| {
+            self.expect_vacant(&whiteouts, path)?;
+            self.ensure_upper_directory(&path.parent().unwrap_or_default())?;
+
+            to_result(self.upper.create_file(path, size))
+        })())
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        match self.expect_type(&whiteouts, path, FsEntryType::File)? {
+            Layer::Base if !is_writable(mode) => self.base.open_file(path, mode),
+            Layer::Base => {
+                self.copy_up(path, path)?;
+                self.upper.open_file(path, mode)
+            },
+            Layer::Upper => self.upper.open_file(path, mode),
+        }
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        let mut whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result((|| {
+            let layer = self.expect_type(&whiteouts, path, FsEntryType::File)?;
+
+            if path == new_path {
+                return Ok(());
+            }
+
+            self.expect_vacant(&whiteouts, new_path)?;
+
+            match layer {
+                Layer::Upper => {
+                    self.ensure_upper_directory(&new_path.parent().unwrap_or_default())?;
+                    to_result(self.upper.rename_file(path, new_path))?;
+                },
+                Layer::Base => self.copy_up(path, new_path)?,
+            }
+
+            self.remove(&mut whiteouts, path)
+        })())
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        let mut whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result(self.expect_type(&whiteouts, path, FsEntryType::File).and_then(|_| self.remove(&mut whiteouts, path)))
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result(self.expect_vacant(&whiteouts, path).and_then(|_| self.ensure_upper_directory(path)))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        self.expect_type(&whiteouts, path, FsEntryType::Directory)?;
+
+        Ok(Box::new(DirectoryListing::new(self.list(&whiteouts, path)?, mode)))
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        let mut whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&whiteouts, path, FsEntryType::Directory)?;
+
+            if path == new_path {
+                return Ok(());
+            }
+
+            self.expect_vacant(&whiteouts, new_path)?;
+
+            if path.is_root() || new_path.starts_with(path) {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            if self.base_entry_type(&whiteouts, path).is_none() {
+                self.ensure_upper_directory(&new_path.parent().unwrap_or_default())?;
+                return to_result(self.upper.rename_directory(path, new_path));
+            }
+
+            self.copy_directory(&whiteouts, path, new_path)?;
+            self.remove(&mut whiteouts, path)
+        })())
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        let mut whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&whiteouts, path, FsEntryType::Directory)?;
+
+            if path.is_root() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            if !self.list(&whiteouts, path)?.is_empty() {
+                return Err(AccessorResult::DirectoryNotEmpty);
+            }
+
+            self.remove(&mut whiteouts, path)
+        })())
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        let mut whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&whiteouts, path, FsEntryType::Directory)?;
+
+            if path.is_root() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            self.remove(&mut whiteouts, path)
+        })())
+    }
+
+    fn get_free_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.upper.get_free_space_size(path)
+    }
+
+    fn get_total_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.upper.get_total_space_size(path)
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        match self.expect_type(&whiteouts, path, FsEntryType::File)? {
+            Layer::Upper => self.upper.get_file_time_stamp(path),
+            Layer::Base => self.base.get_file_time_stamp(path),
+        }
+    }
+
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        match self.entry_type(&whiteouts, path) {
+            Ok((_, Layer::Upper)) => self.upper.query_entry(output, input, query_id, path),
+            Ok((_, Layer::Base)) => self.base.query_entry(output, input, query_id, path),
+            Err(e) => e,
+        }
+    }
+
+    fn commit(&self) -> AccessorResult {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result(to_result(self.upper.commit()).map(|_| {
+            *self.committed_whiteouts.lock().unwrap() = (whiteouts.clone(), None);
+        }))
+    }
+
+    fn commit_provisionally(&self, counter: u64) -> AccessorResult {
+        let whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result(to_result(self.upper.commit_provisionally(counter)).map(|_| {
+            self.committed_whiteouts.lock().unwrap().1 = Some(whiteouts.clone());
+        }))
+    }
+
+    fn rollback(&self) -> AccessorResult {
+        let mut whiteouts = self.whiteouts.lock().unwrap();
+
+        into_result(to_result(self.upper.rollback()).map(|_| {
+            let (committed, provisional) = &*self.committed_whiteouts.lock().unwrap();
+
+            *whiteouts = provisional.as_ref().unwrap_or(committed).clone();
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_with, path, read_path, JournalingFileSystem, APPEND, WRITE };
+
+    fn base() -> MemoryFileSystem {
+        memory_with(&[("dir/a", b"base a"), ("dir/b", b"base b"), ("file", b"base")])
+    }
+
+    fn overwrite(fs: &dyn FileSystemAccessor, file: &str, data: &[u8]) {
+        let mut file = fs.open_file(&path(file), WRITE | APPEND).unwrap();
+
+        file.set_size(0).unwrap();
+        file.write(data, 0, true).unwrap();
+    }
+
+    #[test]
+    fn copies_up_on_write() {
+        let fs = CopyOnWriteFileSystem::new(base());
+
+        assert_eq!(read_path(&fs, "dir/a").unwrap(), b"base a");
+        assert!(fs.upper().get_entry_type(&path("dir/a")).is_err());
+
+        overwrite(&fs, "dir/a", b"changed");
+        assert_eq!(read_path(&fs, "dir/a").unwrap(), b"changed");
+        assert_eq!(read_path(fs.upper(), "dir/a").unwrap(), b"changed");
+        assert_eq!(read_path(fs.base(), "dir/a").unwrap(), b"base a");
+
+        assert_eq!(list_path(&fs, "dir").unwrap(), vec![("a".to_owned(), Some(7)), ("b".to_owned(), Some(6))]);
+    }
+
+    #[test]
+    fn whiteouts_hide_deleted_entries() {
+        let fs = CopyOnWriteFileSystem::new(base());
+
+        assert_eq!(fs.delete_file(&path("file")), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "file").err(), Some(AccessorResult::PathNotFound));
+        assert_eq!(read_path(fs.base(), "file").unwrap(), b"base");
+
+        assert_eq!(fs.delete_directory_recursively(&path("dir")), AccessorResult::Success);
+        assert!(fs.get_entry_type(&path("dir/a")).is_err());
+        assert!(list_path(&fs, "").unwrap().is_empty());
+
+        // Recreating a deleted directory doesn't bring back what the base has in it
+        assert_eq!(fs.create_directory(&path("dir")), AccessorResult::Success);
+        assert!(list_path(&fs, "dir").unwrap().is_empty());
+        assert_eq!(fs.create_file(&path("file"), 0), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "file").unwrap(), b"");
+    }
+
+    #[test]
+    fn renames_copy_up_and_hide_the_source() {
+        let fs = CopyOnWriteFileSystem::new(base());
+
+        assert_eq!(fs.rename_file(&path("file"), &path("dir/renamed")), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "dir/renamed").unwrap(), b"base");
+        assert!(fs.get_entry_type(&path("file")).is_err());
+
+        assert_eq!(fs.rename_directory(&path("dir"), &path("moved")), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "moved/a").unwrap(), b"base a");
+        assert_eq!(read_path(&fs, "moved/renamed").unwrap(), b"base");
+        assert!(fs.get_entry_type(&path("dir")).is_err());
+        assert!(fs.base().get_entry_type(&path("dir/a")).is_ok());
+    }
+
+    #[test]
+    fn rollback_follows_the_upper_layer() {
+        let fs = CopyOnWriteFileSystem::with_upper(base(), JournalingFileSystem::new(MemoryFileSystem::new()));
+
+        overwrite(&fs, "file", b"committed");
+        assert_eq!(fs.delete_file(&path("dir/a")), AccessorResult::Success);
+        assert_eq!(fs.commit(), AccessorResult::Success);
+
+        overwrite(&fs, "file", b"provisional");
+        assert_eq!(fs.delete_file(&path("dir/b")), AccessorResult::Success);
+        assert_eq!(fs.commit_provisionally(1), AccessorResult::Success);
+
+        overwrite(&fs, "file", b"discarded");
+        assert_eq!(fs.delete_directory_recursively(&path("dir")), AccessorResult::Success);
+
+        assert_eq!(fs.rollback(), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "file").unwrap(), b"provisional");
+        assert_eq!(list_path(&fs, "dir").unwrap(), vec![]);
+
+        assert_eq!(fs.commit(), AccessorResult::Success);
+        assert_eq!(fs.rollback(), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "file").unwrap(), b"provisional");
+        assert!(fs.get_entry_type(&path("dir/b")).is_err());
+    }
+
+    #[test]
+    fn rollback_without_upper_support_keeps_changes() {
+        let fs = CopyOnWriteFileSystem::new(base());
+
+        assert_eq!(fs.delete_file(&path("file")), AccessorResult::Success);
+        assert_eq!(fs.rollback(), AccessorResult::Unsupported);
+        assert!(fs.get_entry_type(&path("file")).is_err());
+    }
+}
@@ -0,0 +1,408 @@
+use skyline::nn;
+
+use super::compression::{ Compression, SIZE_HEADER_LENGTH };
+use super::{ is_writable, read_to_end };
+#[cfg(feature = "zstd")]
+use super::seekable_zstd::{ read_seek_table, SeekableZstdFile };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, OperateRangeId, QueryEntryId, QueryRangeInfo};
+
+enum Contents<F: FileAccessor> {
+    Stored(F),
+    /// Decompressed whole on first access
+    Compressed {
+        file: F,
+        compression: Compression,
+        data: Option<Vec<u8>>,
+    },
+    #[cfg(feature = "zstd")]
+    Seekable(SeekableZstdFile<F>),
+}
+
+/// Presents the decompressed contents of a Yaz0, zstd or LZ4 compressed file.
+///
+/// The whole file is decompressed into memory on first access, unless it is in the zstd seekable format in which case only the frames being read are.
+/// Files that aren't compressed are passed through untouched.
+pub struct DecompressingFile<F: FileAccessor> {
+    contents: Contents<F>,
+}
+
+impl<F: FileAccessor> DecompressingFile<F> {
+    /// Wrap `file`, detecting its compression from its magic.
+    pub fn new(mut file: F) -> Result<Self, AccessorResult> {
+        let mut magic = [0; 4];
+        let size = file.read(&mut magic, 0)?;
+
+        Self::with_compression(file, Compression::detect(&magic[..size]))
+    }
+
+    /// Wrap `file`, which is known to be compressed with `compression`, or not at all for `None`.
+    pub fn with_compression(file: F, compression: Option<Compression>) -> Result<Self, AccessorResult> {
+        let contents = match compression {
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd) => {
+                let mut file = file;
+
+                match read_seek_table(&mut file)? {
+                    Some(frames) => Contents::Seekable(SeekableZstdFile::with_frames(file, frames)),
+                    None => Contents::Compressed { file, compression: Compression::Zstd, data: None },
+                }
+            },
+            Some(compression) => Contents::Compressed { file, compression, data: None },
+            None => Contents::Stored(file),
+        };
+
+        Ok(Self {
+            contents,
+        })
+    }
+
+    pub fn compression(&self) -> Option<Compression> {
+        match &self.contents {
+            Contents::Stored(_) => None,
+            Contents::Compressed { compression, .. } => Some(*compression),
+            #[cfg(feature = "zstd")]
+            Contents::Seekable(_) => Some(Compression::Zstd),
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        match self.contents {
+            Contents::Stored(file) | Contents::Compressed { file, .. } => file,
+            #[cfg(feature = "zstd")]
+            Contents::Seekable(file) => file.into_inner(),
+        }
+    }
+}
+
+/// Contents of a file decompressed whole, decompressing them on first access.
+fn decompressed<'a>(file: &mut dyn FileAccessor, compression: Compression, data: &'a mut Option<Vec<u8>>) -> Result<&'a [u8], AccessorResult> {
+    if data.is_none() {
+        *data = Some(compression.decompress(&read_to_end(file)?)?);
+    }
+
+    Ok(data.as_ref().unwrap())
+}
+
+impl<F: FileAccessor> FileAccessor for DecompressingFile<F> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        let data = match &mut self.contents {
+            Contents::Stored(file) => return file.read(buffer, offset),
+            Contents::Compressed { file, compression, data } => decompressed(file, *compression, data)?,
+            #[cfg(feature = "zstd")]
+            Contents::Seekable(file) => return file.read(buffer, offset),
+        };
+
+        if offset >= data.len() {
+            return Ok(0);
+        }
+
+        let size = buffer.len().min(data.len() - offset);
+
+        buffer[..size].copy_from_slice(&data[offset..offset + size]);
+        Ok(size)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        match &mut self.contents {
+            Contents::Stored(file) => file.write(data, offset, should_append),
+            _ => Err(AccessorResult::Unsupported),
+        }
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        match &mut self.contents {
+            Contents::Stored(file) => file.set_size(new_size),
+            _ => Err(AccessorResult::Unsupported),
+        }
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        match &mut self.contents {
+            Contents::Stored(file) => file.get_size(),
+            Contents::Compressed { file, compression, data } => Ok(decompressed(file, *compression, data)?.len()),
+            #[cfg(feature = "zstd")]
+            Contents::Seekable(file) => file.get_size(),
+        }
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        match &mut self.contents {
+            Contents::Stored(file) => file.flush(),
+            _ => AccessorResult::Success,
+        }
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        match (&mut self.contents, operation) {
+            (Contents::Stored(file), _) => file.operate_range(operation, offset, size, info),
+            #[cfg(feature = "zstd")]
+            (Contents::Seekable(file), _) => file.operate_range(operation, offset, size, info),
+            (Contents::Compressed { data, .. }, OperateRangeId::Invalidate) => {
+                *data = None;
+                AccessorResult::Success
+            },
+            (_, OperateRangeId::QueryRange) => AccessorResult::Success,
+            _ => AccessorResult::Unsupported,
+        }
+    }
+}
+
+/// Wraps a `FileSystemAccessor` and transparently decompresses the files opened for reading through it.
+///
+/// Files are only ever decompressed when opened read-only, writable opens get the stored bytes.
+/// Directory listings report the decompressed size recorded in the headers of compressed files, which means opening each of them.
+/// Files whose headers don't record it keep their stored size, such as zstd files compressed as a stream without a seek table.
+pub struct DecompressingFileSystem<A: FileSystemAccessor> {
+    inner: A,
+    by_extension: bool,
+}
+
+impl<A: FileSystemAccessor> DecompressingFileSystem<A> {
+    /// Decompress every file whose magic matches a supported compression.
+    pub fn new(inner: A) -> Self {
+        Self {
+            inner,
+            by_extension: false,
+        }
+    }
+
+    /// Only decompress files with an extension naming a compression, such as `.yaz0`, `.szs`, `.zs`, `.zst` or `.lz4`, which saves reading the start of every other file.
+    pub fn by_extension(inner: A) -> Self {
+        Self {
+            inner,
+            by_extension: true,
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Decompressed size of the file at `path` going by its headers, or `None` if it isn't compressed or they don't say.
+    fn decompressed_size(&self, path: &NnPath) -> Result<Option<usize>, AccessorResult> {
+        if self.by_extension && path.file_name().and_then(Compression::from_extension).is_none() {
+            return Ok(None);
+        }
+
+        let mut file = self.inner.open_file(path, nn::fs::OpenMode_OpenMode_Read)?;
+        let mut header = [0; SIZE_HEADER_LENGTH];
+        let size = file.read(&mut header, 0)?;
+
+        match Compression::detect(&header[..size]) {
+            #[cfg(feature = "zstd")]
+            Some(Compression::Zstd) => match read_seek_table(&mut file)? {
+                Some(frames) => SeekableZstdFile::with_frames(file, frames).get_size().map(Some),
+                None => Ok(Compression::Zstd.decompressed_size(&header[..size])),
+            },
+            Some(compression) => Ok(compression.decompressed_size(&header[..size])),
+            None => Ok(None),
+        }
+    }
+}
+
+impl<A: FileSystemAccessor> FileSystemAccessor for DecompressingFileSystem<A> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.inner.get_entry_type(path)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.inner.create_file(path, size)
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let file = self.inner.open_file(path, mode)?;
+
+        if is_writable(mode) {
+            return Ok(file);
+        }
+
+        if self.by_extension {
+            match path.file_name().and_then(Compression::from_extension) {
+                Some(compression) => Ok(Box::new(DecompressingFile::with_compression(file, Some(compression))?)),
+                None => Ok(file),
+            }
+        } else {
+            Ok(Box::new(DecompressingFile::new(file)?))
+        }
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.inner.rename_file(path, new_path)
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        self.inner.delete_file(path)
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        self.inner.create_directory(path)
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let mut entries = crate::accessors::read_all_entries(&mut *self.inner.open_directory(path, mode)?)?;
+
+        for entry in &mut entries {
+            if let DirectoryEntryType::File(size) = &mut entry.ty {
+                let file = match entry.path.file_name().and_then(|name| name.to_str()).map(|name| path.join(name)) {
+                    Some(Ok(file)) => file,
+                    _ => continue,
+                };
+
+                // Files that can't be opened are listed as they are, their size is only informative
+                if let Ok(Some(decompressed)) = self.decompressed_size(&file) {
+                    *size = decompressed as i64;
+                }
+            }
+        }
+
+        Ok(Box::new(DirectoryListing::new(entries, mode)))
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.inner.rename_directory(path, new_path)
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        self.inner.delete_directory(path)
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.inner.delete_directory_recursively(path)
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.inner.clean_directory_recursively(path)
+    }
+
+    fn get_free_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.inner.get_free_space_size(path)
+    }
+
+    fn get_total_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.inner.get_total_space_size(path)
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        self.inner.get_file_time_stamp(path)
+    }
+
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        self.inner.query_entry(output, input, query_id, path)
+    }
+
+    fn commit(&self) -> AccessorResult {
+        self.inner.commit()
+    }
+
+    fn commit_provisionally(&self, counter: u64) -> AccessorResult {
+        self.inner.commit_provisionally(counter)
+    }
+
+    fn rollback(&self) -> AccessorResult {
+        self.inner.rollback()
+    }
+
+    fn check_free_space(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.inner.check_free_space(path, size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_with, path, read_path, READ, WRITE };
+
+    fn yaz0(data: &[u8]) -> Vec<u8> {
+        let mut compressed = b"Yaz0".to_vec();
+        compressed.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        compressed.extend_from_slice(&[0; 8]);
+
+        // Literals only, eight per group
+        for chunk in data.chunks(8) {
+            compressed.push(0xff);
+            compressed.extend_from_slice(chunk);
+        }
+
+        compressed
+    }
+
+    fn contents() -> Vec<u8> {
+        (0..3000).map(|idx| (idx % 251) as u8).collect()
+    }
+
+    #[test]
+    fn yaz0_round_trip() {
+        let fs = DecompressingFileSystem::new(memory_with(&[("data.szs", &yaz0(&contents())), ("plain.bin", b"plain")]));
+
+        assert_eq!(read_path(&fs, "data.szs"), Ok(contents()));
+        assert_eq!(read_path(&fs, "plain.bin"), Ok(b"plain".to_vec()));
+        assert_eq!(list_path(&fs, ""), Ok(vec![("data.szs".to_string(), Some(3000)), ("plain.bin".to_string(), Some(5))]));
+    }
+
+    #[test]
+    fn writable_opens_get_the_stored_bytes() {
+        let compressed = yaz0(b"contents");
+        let fs = DecompressingFileSystem::new(memory_with(&[("data.szs", &compressed)]));
+
+        let mut file = fs.open_file(&path("data.szs"), READ | WRITE).unwrap();
+        assert_eq!(read_to_end(&mut *file), Ok(compressed));
+    }
+
+    #[test]
+    fn by_extension_only_decompresses_named_files() {
+        let compressed = yaz0(b"contents");
+        let fs = DecompressingFileSystem::by_extension(memory_with(&[("data.szs", &compressed), ("data.bin", &compressed)]));
+
+        assert_eq!(read_path(&fs, "data.szs"), Ok(b"contents".to_vec()));
+        assert_eq!(read_path(&fs, "data.bin"), Ok(compressed.clone()));
+        assert_eq!(list_path(&fs, ""), Ok(vec![("data.bin".to_string(), Some(compressed.len() as i64)), ("data.szs".to_string(), Some(8))]));
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn lz4_round_trip() {
+        use std::io::Write;
+
+        let compress = |content_size: Option<u64>| {
+            let info = lz4_flex::frame::FrameInfo::new().content_size(content_size);
+            let mut encoder = lz4_flex::frame::FrameEncoder::with_frame_info(info, Vec::new());
+
+            encoder.write_all(&contents()).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let (sized, unknown) = (compress(Some(3000)), compress(None));
+        let fs = DecompressingFileSystem::new(memory_with(&[("sized.lz4", &sized), ("unknown.lz4", &unknown)]));
+
+        assert_eq!(read_path(&fs, "sized.lz4"), Ok(contents()));
+        assert_eq!(read_path(&fs, "unknown.lz4"), Ok(contents()));
+        assert_eq!(list_path(&fs, ""), Ok(vec![("sized.lz4".to_string(), Some(3000)), ("unknown.lz4".to_string(), Some(unknown.len() as i64))]));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_round_trip() {
+        use crate::backends::compress_seekable_zstd;
+
+        // A single segment frame holding one raw block, with its content size in the header
+        let mut raw = vec![0x28, 0xb5, 0x2f, 0xfd, 0x20, 5];
+        raw.extend_from_slice(&(5 << 3 | 1u32).to_le_bytes()[..3]);
+        raw.extend_from_slice(b"hello");
+
+        let stream = ruzstd::encoding::compress_to_vec(&contents()[..], ruzstd::encoding::CompressionLevel::Fastest);
+        let seekable = compress_seekable_zstd(&contents(), 1024);
+
+        let fs = DecompressingFileSystem::new(memory_with(&[("raw.zs", &raw), ("seekable.zs", &seekable), ("stream.zs", &stream)]));
+
+        assert_eq!(read_path(&fs, "raw.zs"), Ok(b"hello".to_vec()));
+        assert_eq!(read_path(&fs, "seekable.zs"), Ok(contents()));
+        assert_eq!(read_path(&fs, "stream.zs"), Ok(contents()));
+
+        // The stream doesn't record its content size
+        assert_eq!(list_path(&fs, ""), Ok(vec![
+            ("raw.zs".to_string(), Some(5)),
+            ("seekable.zs".to_string(), Some(3000)),
+            ("stream.zs".to_string(), Some(stream.len() as i64)),
+        ]));
+    }
+}
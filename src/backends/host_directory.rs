@@ -0,0 +1,388 @@
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use skyline::nn;
+
+use super::into_result;
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, OperateRangeId, QueryRangeInfo};
+
+pub(super) fn to_accessor_result(error: io::Error) -> AccessorResult {
+    match error.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::InvalidInput => AccessorResult::PathNotFound,
+        io::ErrorKind::AlreadyExists => AccessorResult::PathAlreadyExists,
+        // Read-only files and folders, the SDK reports writes it doesn't allow the same way
+        io::ErrorKind::PermissionDenied => AccessorResult::Unsupported,
+        _ => AccessorResult::Unexpected,
+    }
+}
+
+fn seconds_since_epoch(time: io::Result<SystemTime>) -> i64 {
+    time.ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+fn file_time_stamp(metadata: &Metadata) -> FileTimeStamp {
+    FileTimeStamp {
+        created: seconds_since_epoch(metadata.created()),
+        modified: seconds_since_epoch(metadata.modified()),
+        accessed: seconds_since_epoch(metadata.accessed()),
+        is_local_time: false,
+    }
+}
+
+/// Exposes a directory of the host filesystem, such as a folder on the SD card, through `std::fs`.
+pub struct HostDirectoryFileSystem {
+    root: PathBuf,
+}
+
+impl HostDirectoryFileSystem {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Self {
+        Self {
+            root: root.into(),
+        }
+    }
+
+    pub fn root(&self) -> &std::path::Path {
+        &self.root
+    }
+
+    fn resolve(&self, path: &NnPath) -> PathBuf {
+        self.root.join(path.as_path())
+    }
+
+    fn metadata(&self, path: &NnPath) -> Result<Metadata, AccessorResult> {
+        fs::metadata(self.resolve(path)).map_err(to_accessor_result)
+    }
+
+    fn expect_type(&self, path: &NnPath, ty: FsEntryType) -> Result<PathBuf, AccessorResult> {
+        if self.get_entry_type(path)? == ty {
+            Ok(self.resolve(path))
+        } else {
+            Err(AccessorResult::PathNotFound)
+        }
+    }
+
+    /// Check that `path` doesn't exist yet but its parent directory does.
+    fn expect_vacant(&self, path: &NnPath) -> Result<PathBuf, AccessorResult> {
+        let parent = path.parent().ok_or(AccessorResult::PathAlreadyExists)?;
+
+        self.expect_type(&parent, FsEntryType::Directory)?;
+
+        match self.get_entry_type(path) {
+            Ok(_) => Err(AccessorResult::PathAlreadyExists),
+            Err(_) => Ok(self.resolve(path)),
+        }
+    }
+
+    fn clean(&self, directory: &std::path::Path) -> Result<(), AccessorResult> {
+        for entry in fs::read_dir(directory).map_err(to_accessor_result)? {
+            let entry = entry.map_err(to_accessor_result)?;
+
+            if entry.file_type().map_err(to_accessor_result)?.is_dir() {
+                fs::remove_dir_all(entry.path()).map_err(to_accessor_result)?;
+            } else {
+                fs::remove_file(entry.path()).map_err(to_accessor_result)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FileSystemAccessor for HostDirectoryFileSystem {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        if self.metadata(path)?.is_dir() {
+            Ok(FsEntryType::Directory)
+        } else {
+            Ok(FsEntryType::File)
+        }
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        into_result((|| {
+            let file = OpenOptions::new().write(true).create_new(true).open(self.expect_vacant(path)?).map_err(to_accessor_result)?;
+
+            file.set_len(size as u64).map_err(to_accessor_result)
+        })())
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let path = self.expect_type(path, FsEntryType::File)?;
+        let file = OpenOptions::new()
+            .read(mode as u32 & nn::fs::OpenMode_OpenMode_Read as u32 != 0)
+            .write(mode as u32 & nn::fs::OpenMode_OpenMode_Write as u32 != 0)
+            .open(path)
+            .map_err(to_accessor_result)?;
+
+        Ok(Box::new(HostFile::new(file, mode)))
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        into_result((|| {
+            let from = self.expect_type(path, FsEntryType::File)?;
+
+            if path == new_path {
+                return Ok(());
+            }
+
+            fs::rename(from, self.expect_vacant(new_path)?).map_err(to_accessor_result)
+        })())
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        into_result(self.expect_type(path, FsEntryType::File).and_then(|path| fs::remove_file(path).map_err(to_accessor_result)))
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        into_result(self.expect_vacant(path).and_then(|path| fs::create_dir(path).map_err(to_accessor_result)))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let mut entries = Vec::new();
+
+        for entry in fs::read_dir(self.expect_type(path, FsEntryType::Directory)?).map_err(to_accessor_result)? {
+            let entry = entry.map_err(to_accessor_result)?;
+            let metadata = entry.metadata().map_err(to_accessor_result)?;
+
+            entries.push(DirectoryEntry {
+                path: PathBuf::from(entry.file_name()),
+                ty: if metadata.is_dir() { DirectoryEntryType::Directory } else { DirectoryEntryType::File(metadata.len() as i64) },
+                timestamp: Some(file_time_stamp(&metadata)),
+            });
+        }
+
+        // read_dir doesn't guarantee any order, sort the listing so it stays stable between calls
+        entries.sort_by(|a, b| a.path.cmp(&b.path));
+
+        Ok(Box::new(DirectoryListing::new(entries, mode)))
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        into_result((|| {
+            let from = self.expect_type(path, FsEntryType::Directory)?;
+
+            if path == new_path {
+                return Ok(());
+            }
+
+            let to = self.expect_vacant(new_path)?;
+
+            if path.is_root() || new_path.starts_with(path) {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            fs::rename(from, to).map_err(to_accessor_result)
+        })())
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        into_result((|| {
+            let directory = self.expect_type(path, FsEntryType::Directory)?;
+
+            if path.is_root() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            if fs::read_dir(&directory).map_err(to_accessor_result)?.next().is_some() {
+                return Err(AccessorResult::DirectoryNotEmpty);
+            }
+
+            fs::remove_dir(directory).map_err(to_accessor_result)
+        })())
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        into_result((|| {
+            let directory = self.expect_type(path, FsEntryType::Directory)?;
+
+            if path.is_root() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            fs::remove_dir_all(directory).map_err(to_accessor_result)
+        })())
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        into_result(self.expect_type(path, FsEntryType::Directory).and_then(|directory| self.clean(&directory)))
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        self.expect_type(path, FsEntryType::File)?;
+
+        Ok(file_time_stamp(&self.metadata(path)?))
+    }
+}
+
+/// A `std::fs::File` exposed as a `FileAccessor`, restricted to what `mode` allows.
+pub struct HostFile {
+    file: File,
+    mode: nn::fs::OpenMode,
+}
+
+impl HostFile {
+    pub fn new(file: File, mode: nn::fs::OpenMode) -> Self {
+        Self {
+            file,
+            mode,
+        }
+    }
+
+    /// Open the file at `path` for reading.
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        Ok(Self::new(File::open(path)?, nn::fs::OpenMode_OpenMode_Read))
+    }
+
+    fn allows(&self, mode: nn::fs::OpenMode) -> bool {
+        self.mode as u32 & mode as u32 != 0
+    }
+
+    fn expect_mode(&self, mode: nn::fs::OpenMode) -> Result<(), AccessorResult> {
+        if self.allows(mode) {
+            Ok(())
+        } else {
+            Err(AccessorResult::Unsupported)
+        }
+    }
+}
+
+impl FileAccessor for HostFile {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        self.expect_mode(nn::fs::OpenMode_OpenMode_Read)?;
+        self.file.seek(SeekFrom::Start(offset as u64)).map_err(to_accessor_result)?;
+
+        let mut size = 0;
+
+        while size < buffer.len() {
+            match self.file.read(&mut buffer[size..]) {
+                Ok(0) => break,
+                Ok(read) => size += read,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(e) => return Err(to_accessor_result(e)),
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        self.expect_mode(nn::fs::OpenMode_OpenMode_Write)?;
+
+        if !self.allows(nn::fs::OpenMode_OpenMode_Append) && offset + data.len() > self.get_size()? {
+            return Err(AccessorResult::Unsupported);
+        }
+
+        self.file.seek(SeekFrom::Start(offset as u64)).map_err(to_accessor_result)?;
+        self.file.write_all(data).map_err(to_accessor_result)
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        self.expect_mode(nn::fs::OpenMode_OpenMode_Write)?;
+
+        self.file.set_len(new_size as u64).map_err(to_accessor_result)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.file.metadata().map_err(to_accessor_result)?.len() as usize)
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        into_result(self.file.flush().map_err(to_accessor_result))
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        match operation {
+            // The host OS keeps its own caches coherent, nothing to invalidate or report
+            OperateRangeId::Invalidate | OperateRangeId::QueryRange => AccessorResult::Success,
+            _ => AccessorResult::Unsupported,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accessors::read_all_entries;
+    use crate::backends::{ path, APPEND, READ, WRITE };
+
+    /// A scratch directory under the system temporary directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("nn-fuse-{}-{}", name, std::process::id()));
+
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(&path).unwrap();
+            TempDir(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn creates_renames_and_deletes() {
+        let temp = TempDir::new("entries");
+        let fs = HostDirectoryFileSystem::new(&temp.0);
+
+        assert_eq!(fs.create_directory(&path("dir")), AccessorResult::Success);
+        assert_eq!(fs.create_directory(&path("dir")), AccessorResult::PathAlreadyExists);
+        assert_eq!(fs.create_file(&path("dir/file"), 3), AccessorResult::Success);
+        assert_eq!(fs.create_file(&path("missing/file"), 0), AccessorResult::PathNotFound);
+        assert_eq!(std::fs::metadata(temp.0.join("dir/file")).unwrap().len(), 3);
+
+        assert_eq!(fs.rename_file(&path("dir/file"), &path("dir/renamed")), AccessorResult::Success);
+        assert_eq!(fs.rename_directory(&path("dir"), &path("dir/sub")), AccessorResult::Unsupported);
+        assert_eq!(fs.rename_directory(&path("dir"), &path("moved")), AccessorResult::Success);
+        assert_eq!(fs.get_entry_type(&path("moved/renamed")), Ok(FsEntryType::File));
+
+        let entries = read_all_entries(&mut *fs.open_directory(&path("moved"), nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).unwrap()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert!(matches!(entries[0].ty, DirectoryEntryType::File(3)));
+
+        assert_eq!(fs.delete_directory(&path("moved")), AccessorResult::DirectoryNotEmpty);
+        assert_eq!(fs.clean_directory_recursively(&path("moved")), AccessorResult::Success);
+        assert_eq!(fs.delete_directory(&path("moved")), AccessorResult::Success);
+        assert_eq!(fs.delete_directory_recursively(&NnPath::root()), AccessorResult::Unsupported);
+        assert!(fs.get_entry_type(&path("moved")).is_err());
+    }
+
+    #[test]
+    fn files_are_restricted_to_their_open_mode() {
+        let temp = TempDir::new("modes");
+        let fs = HostDirectoryFileSystem::new(&temp.0);
+        let mut buffer = [0; 8];
+
+        assert_eq!(fs.create_file(&path("file"), 4), AccessorResult::Success);
+
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+        assert_eq!(file.read(&mut buffer, 0), Ok(4));
+        assert_eq!(file.write(b"data", 0, false).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.set_size(0).err(), Some(AccessorResult::Unsupported));
+
+        let mut file = fs.open_file(&path("file"), WRITE).unwrap();
+        assert_eq!(file.read(&mut buffer, 0).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.write(b"data", 0, false), Ok(()));
+        assert_eq!(file.write(b"more", 2, false).err(), Some(AccessorResult::Unsupported));
+
+        let mut file = fs.open_file(&path("file"), WRITE | APPEND).unwrap();
+        assert_eq!(file.write(b"more", 4, false), Ok(()));
+        drop(file);
+
+        assert_eq!(std::fs::read(temp.0.join("file")).unwrap(), b"datamore");
+    }
+
+    #[test]
+    fn permission_errors_are_not_reported_as_in_use() {
+        assert_eq!(to_accessor_result(io::Error::from(io::ErrorKind::PermissionDenied)), AccessorResult::Unsupported);
+        assert_eq!(to_accessor_result(io::Error::from(io::ErrorKind::NotFound)), AccessorResult::PathNotFound);
+    }
+}
@@ -0,0 +1,600 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use super::{ expect_mode, into_result, read_to_end, to_result };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, OperateRangeId, QueryEntryId, QueryRangeInfo};
+
+#[derive(Clone)]
+enum Pending {
+    File(Vec<u8>),
+    /// An opaque directory hides whatever the base filesystem has under the same path.
+    Directory { opaque: bool },
+    Deleted,
+}
+
+#[derive(Default)]
+struct Journal {
+    entries: BTreeMap<NnPath, Pending>,
+    provisional: Option<(u64, BTreeMap<NnPath, Pending>)>,
+}
+
+/// Wraps a `FileSystemAccessor` and buffers every write, creation and deletion in memory until `commit` applies them to it.
+///
+/// `rollback` discards the buffered changes, going back to the state of the last `commit_provisionally` if there was one since the last `commit`.
+pub struct JournalingFileSystem<A: FileSystemAccessor> {
+    base: Arc<A>,
+    journal: Arc<Mutex<Journal>>,
+}
+
+fn read_base_file<A: FileSystemAccessor>(base: &A, path: &NnPath) -> Result<Vec<u8>, AccessorResult> {
+    read_to_end(&mut *base.open_file(path, nn::fs::OpenMode_OpenMode_Read)?)
+}
+
+impl<A: FileSystemAccessor + 'static> JournalingFileSystem<A> {
+    pub fn new(base: A) -> Self {
+        Self {
+            base: Arc::new(base),
+            journal: Arc::new(Mutex::new(Journal::default())),
+        }
+    }
+
+    /// The counter passed to the last `commit_provisionally`, if there was one since the last `commit`.
+    pub fn provisional_commit_counter(&self) -> Option<u64> {
+        self.journal.lock().unwrap().provisional.as_ref().map(|(counter, _)| *counter)
+    }
+
+    /// Whether the base filesystem's entry at `path` is hidden by a pending change to one of its ancestors.
+    fn is_hidden(journal: &Journal, path: &NnPath) -> bool {
+        path.ancestors().skip(1).any(|ancestor| match journal.entries.get(&ancestor) {
+            Some(Pending::Directory { opaque }) => *opaque,
+            Some(_) => true,
+            None => false,
+        })
+    }
+
+    fn base_entry_type(&self, journal: &Journal, path: &NnPath) -> Option<FsEntryType> {
+        if Self::is_hidden(journal, path) {
+            None
+        } else {
+            self.base.get_entry_type(path).ok()
+        }
+    }
+
+    fn entry_type(&self, journal: &Journal, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        match journal.entries.get(path) {
+            Some(Pending::File(_)) => Ok(FsEntryType::File),
+            Some(Pending::Directory { .. }) => Ok(FsEntryType::Directory),
+            Some(Pending::Deleted) => Err(AccessorResult::PathNotFound),
+            None => self.base_entry_type(journal, path).ok_or(AccessorResult::PathNotFound),
+        }
+    }
+
+    fn expect_type(&self, journal: &Journal, path: &NnPath, ty: FsEntryType) -> Result<(), AccessorResult> {
+        if self.entry_type(journal, path)? == ty {
+            Ok(())
+        } else {
+            Err(AccessorResult::PathNotFound)
+        }
+    }
+
+    /// Check that `path` doesn't exist yet but its parent directory does.
+    fn expect_vacant(&self, journal: &Journal, path: &NnPath) -> Result<(), AccessorResult> {
+        let parent = path.parent().ok_or(AccessorResult::PathAlreadyExists)?;
+
+        self.expect_type(journal, &parent, FsEntryType::Directory)?;
+
+        match self.entry_type(journal, path) {
+            Ok(_) => Err(AccessorResult::PathAlreadyExists),
+            Err(_) => Ok(()),
+        }
+    }
+
+    /// Remove `path` and everything under it from the journal, recording a deletion if the base filesystem has it.
+    fn remove(&self, journal: &mut Journal, path: &NnPath) {
+        let in_base = self.base_entry_type(journal, path).is_some();
+
+        journal.entries.retain(|entry, _| !entry.starts_with(path));
+
+        if in_base {
+            journal.entries.insert(path.clone(), Pending::Deleted);
+        }
+    }
+
+    fn list(&self, journal: &Journal, path: &NnPath) -> Result<Vec<DirectoryEntry>, AccessorResult> {
+        let mut entries = BTreeMap::new();
+        let opaque = matches!(journal.entries.get(path), Some(Pending::Directory { opaque: true }));
+
+        if !opaque && self.base_entry_type(journal, path) == Some(FsEntryType::Directory) {
+            let mut directory = self.base.open_directory(path, nn::fs::OpenDirectoryMode_OpenDirectoryMode_All)?;
+
+            for entry in crate::accessors::read_all_entries(&mut *directory)? {
+                if let Some(name) = entry.path.file_name().and_then(|name| name.to_str()) {
+                    entries.insert(name.to_owned(), (entry.ty, entry.timestamp));
+                }
+            }
+        }
+
+        for (entry_path, pending) in journal.entries.iter().filter(|(entry_path, _)| entry_path.parent().as_ref() == Some(path)) {
+            let name = entry_path.file_name().unwrap().to_owned();
+
+            match pending {
+                Pending::File(data) => entries.insert(name, (DirectoryEntryType::File(data.len() as i64), None)),
+                Pending::Directory { .. } => entries.insert(name, (DirectoryEntryType::Directory, None)),
+                Pending::Deleted => entries.remove(&name),
+            };
+        }
+
+        Ok(entries.into_iter().map(|(name, (ty, timestamp))| DirectoryEntry { path: PathBuf::from(name), ty, timestamp }).collect())
+    }
+
+    fn read_file(&self, journal: &Journal, path: &NnPath) -> Result<Vec<u8>, AccessorResult> {
+        match journal.entries.get(path) {
+            Some(Pending::File(data)) => Ok(data.clone()),
+            _ => read_base_file(&*self.base, path),
+        }
+    }
+
+    fn copy_directory(&self, journal: &mut Journal, path: &NnPath, new_path: &NnPath) -> Result<(), AccessorResult> {
+        let entries = self.list(journal, path)?;
+
+        journal.entries.insert(new_path.clone(), Pending::Directory { opaque: true });
+
+        for entry in entries {
+            let name = entry.path.to_str().ok_or(AccessorResult::Unexpected)?;
+            let (entry_path, new_entry_path) = (path.join(name)?, new_path.join(name)?);
+
+            match entry.ty {
+                DirectoryEntryType::Directory => self.copy_directory(journal, &entry_path, &new_entry_path)?,
+                DirectoryEntryType::File(_) => {
+                    let data = self.read_file(journal, &entry_path)?;
+                    journal.entries.insert(new_entry_path, Pending::File(data));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_base_file(&self, path: &NnPath, data: &[u8]) -> Result<(), AccessorResult> {
+        let mut file = self.base.open_file(path, nn::fs::OpenMode_OpenMode_Write | nn::fs::OpenMode_OpenMode_Append)?;
+
+        file.set_size(data.len())?;
+        file.write(data, 0, true)
+    }
+
+    /// Replay the journal onto the base filesystem. Every step checks the current state of the base first, so a failed commit can be retried.
+    fn apply(&self, journal: &Journal) -> Result<(), AccessorResult> {
+        for (path, _) in journal.entries.iter().filter(|(_, pending)| matches!(pending, Pending::Deleted)) {
+            match self.base.get_entry_type(path) {
+                Ok(FsEntryType::Directory) => to_result(self.base.delete_directory_recursively(path))?,
+                Ok(FsEntryType::File) => to_result(self.base.delete_file(path))?,
+                Err(_) => (),
+            }
+        }
+
+        for (path, pending) in journal.entries.iter() {
+            match pending {
+                Pending::Deleted => (),
+                Pending::Directory { opaque } => match self.base.get_entry_type(path) {
+                    Ok(FsEntryType::Directory) if *opaque => to_result(self.base.clean_directory_recursively(path))?,
+                    Ok(FsEntryType::Directory) => (),
+                    Ok(FsEntryType::File) => {
+                        to_result(self.base.delete_file(path))?;
+                        to_result(self.base.create_directory(path))?;
+                    },
+                    Err(_) => to_result(self.base.create_directory(path))?,
+                },
+                Pending::File(data) => {
+                    match self.base.get_entry_type(path) {
+                        Ok(FsEntryType::File) => (),
+                        Ok(FsEntryType::Directory) => {
+                            to_result(self.base.delete_directory_recursively(path))?;
+                            to_result(self.base.create_file(path, data.len()))?;
+                        },
+                        Err(_) => to_result(self.base.create_file(path, data.len()))?,
+                    }
+
+                    self.write_base_file(path, data)?;
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<A: FileSystemAccessor + 'static> FileSystemAccessor for JournalingFileSystem<A> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        let journal = self.journal.lock().unwrap();
+
+        self.entry_type(&journal, path)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result(self.expect_vacant(&journal, path).map(|_| {
+            journal.entries.insert(path.clone(), Pending::File(vec![0; size]));
+        }))
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        self.expect_type(&self.journal.lock().unwrap(), path, FsEntryType::File)?;
+
+        Ok(Box::new(JournaledFile {
+            base: self.base.clone(),
+            journal: self.journal.clone(),
+            path: path.clone(),
+            mode,
+            base_file: None,
+        }))
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&journal, path, FsEntryType::File)?;
+            self.expect_vacant(&journal, new_path)?;
+
+            let data = self.read_file(&journal, path)?;
+            self.remove(&mut journal, path);
+            journal.entries.insert(new_path.clone(), Pending::File(data));
+
+            Ok(())
+        })())
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result(self.expect_type(&journal, path, FsEntryType::File).map(|_| self.remove(&mut journal, path)))
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result(self.expect_vacant(&journal, path).map(|_| {
+            let opaque = matches!(journal.entries.get(path), Some(Pending::Deleted));
+            journal.entries.insert(path.clone(), Pending::Directory { opaque });
+        }))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let journal = self.journal.lock().unwrap();
+
+        self.expect_type(&journal, path, FsEntryType::Directory)?;
+
+        Ok(Box::new(DirectoryListing::new(self.list(&journal, path)?, mode)))
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&journal, path, FsEntryType::Directory)?;
+            self.expect_vacant(&journal, new_path)?;
+
+            if new_path.starts_with(path) {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            self.copy_directory(&mut journal, path, new_path)?;
+            self.remove(&mut journal, path);
+
+            Ok(())
+        })())
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&journal, path, FsEntryType::Directory)?;
+
+            if path.parent().is_none() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            if !self.list(&journal, path)?.is_empty() {
+                return Err(AccessorResult::DirectoryNotEmpty);
+            }
+
+            self.remove(&mut journal, path);
+            Ok(())
+        })())
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result((|| {
+            self.expect_type(&journal, path, FsEntryType::Directory)?;
+
+            if path.parent().is_none() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            self.remove(&mut journal, path);
+            Ok(())
+        })())
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result(self.expect_type(&journal, path, FsEntryType::Directory).map(|_| {
+            self.remove(&mut journal, path);
+            journal.entries.insert(path.clone(), Pending::Directory { opaque: true });
+        }))
+    }
+
+    fn get_free_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.base.get_free_space_size(path)
+    }
+
+    fn get_total_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.base.get_total_space_size(path)
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        let journal = self.journal.lock().unwrap();
+
+        self.expect_type(&journal, path, FsEntryType::File)?;
+
+        // Files that are new or modified in the journal have no timestamps until they are committed
+        match journal.entries.get(path) {
+            Some(_) => Err(AccessorResult::Unsupported),
+            None => self.base.get_file_time_stamp(path),
+        }
+    }
+
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        self.base.query_entry(output, input, query_id, path)
+    }
+
+    fn commit(&self) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        into_result(self.apply(&journal).map(|_| {
+            journal.entries.clear();
+            journal.provisional = None;
+        }))
+    }
+
+    fn commit_provisionally(&self, counter: u64) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        journal.provisional = Some((counter, journal.entries.clone()));
+        AccessorResult::Success
+    }
+
+    fn rollback(&self) -> AccessorResult {
+        let mut journal = self.journal.lock().unwrap();
+
+        journal.entries = match &journal.provisional {
+            Some((_, entries)) => entries.clone(),
+            None => BTreeMap::new(),
+        };
+
+        AccessorResult::Success
+    }
+}
+
+/// A file opened through a `JournalingFileSystem`. Reads fall through to the base filesystem until the first modification copies the file into the journal.
+struct JournaledFile<A: FileSystemAccessor> {
+    base: Arc<A>,
+    journal: Arc<Mutex<Journal>>,
+    path: NnPath,
+    mode: nn::fs::OpenMode,
+    base_file: Option<Box<dyn FileAccessor>>,
+}
+
+impl<A: FileSystemAccessor> JournaledFile<A> {
+    fn base_file(&mut self) -> Result<&mut dyn FileAccessor, AccessorResult> {
+        if self.base_file.is_none() {
+            self.base_file = Some(self.base.open_file(&self.path, nn::fs::OpenMode_OpenMode_Read)?);
+        }
+
+        Ok(&mut **self.base_file.as_mut().unwrap())
+    }
+
+    /// Run `f` on the journaled contents of the file, copying them from the base filesystem first if needed.
+    fn modify<R>(&mut self, f: impl FnOnce(&mut Vec<u8>) -> R) -> Result<R, AccessorResult> {
+        let journal = self.journal.clone();
+        let mut journal = journal.lock().unwrap();
+
+        if !matches!(journal.entries.get(&self.path), Some(Pending::File(_))) {
+            let data = read_to_end(self.base_file()?)?;
+            journal.entries.insert(self.path.clone(), Pending::File(data));
+        }
+
+        match journal.entries.get_mut(&self.path) {
+            Some(Pending::File(data)) => Ok(f(data)),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl<A: FileSystemAccessor> FileAccessor for JournaledFile<A> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Read)?;
+
+        let journal = self.journal.clone();
+        let journal = journal.lock().unwrap();
+
+        if let Some(Pending::File(data)) = journal.entries.get(&self.path) {
+            let data = data.get(offset..).unwrap_or(&[]);
+            let size = data.len().min(buffer.len());
+
+            buffer[..size].copy_from_slice(&data[..size]);
+            return Ok(size);
+        }
+
+        drop(journal);
+        self.base_file()?.read(buffer, offset)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Write)?;
+
+        if offset + data.len() > self.get_size()? {
+            expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Append)?;
+        }
+
+        self.modify(|contents| {
+            if contents.len() < offset + data.len() {
+                contents.resize(offset + data.len(), 0);
+            }
+
+            contents[offset..offset + data.len()].copy_from_slice(data);
+        })
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Write)?;
+
+        self.modify(|contents| contents.resize(new_size, 0))
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        let journal = self.journal.clone();
+        let journal = journal.lock().unwrap();
+
+        if let Some(Pending::File(data)) = journal.entries.get(&self.path) {
+            return Ok(data.len());
+        }
+
+        drop(journal);
+        self.base_file()?.get_size()
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        AccessorResult::Success
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        let journal = self.journal.clone();
+        let journal = journal.lock().unwrap();
+
+        // Journaled contents live in memory, there is nothing to invalidate or report about them
+        if let Some(Pending::File(_)) = journal.entries.get(&self.path) {
+            return match operation {
+                OperateRangeId::Invalidate | OperateRangeId::QueryRange => AccessorResult::Success,
+                _ => AccessorResult::Unsupported,
+            };
+        }
+
+        drop(journal);
+
+        match self.base_file() {
+            Ok(file) => file.operate_range(operation, offset, size, info),
+            Err(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ path, MemoryFileSystem, APPEND, READ, WRITE };
+
+    fn contents(fs: &dyn FileSystemAccessor, file: &str) -> Vec<u8> {
+        read_to_end(&mut *fs.open_file(&path(file), READ).unwrap()).unwrap()
+    }
+
+    fn write(fs: &dyn FileSystemAccessor, file: &str, data: &[u8]) {
+        let mut file = fs.open_file(&path(file), WRITE | APPEND).unwrap();
+
+        file.set_size(0).unwrap();
+        file.write(data, 0, true).unwrap();
+    }
+
+    /// A journal over a memory filesystem holding `base.txt`.
+    fn journaled() -> (MemoryFileSystem, JournalingFileSystem<MemoryFileSystem>) {
+        let base = MemoryFileSystem::new();
+
+        assert_eq!(base.create_file(&path("base.txt"), 0), AccessorResult::Success);
+        write(&base, "base.txt", b"base");
+
+        (base.clone(), JournalingFileSystem::new(base))
+    }
+
+    #[test]
+    fn commit_applies_pending_changes() {
+        let (base, journal) = journaled();
+
+        assert_eq!(journal.create_directory(&path("dir")), AccessorResult::Success);
+        assert_eq!(journal.create_file(&path("dir/new.txt"), 0), AccessorResult::Success);
+        write(&journal, "dir/new.txt", b"new");
+        write(&journal, "base.txt", b"changed");
+
+        assert_eq!(contents(&journal, "base.txt"), b"changed");
+        assert_eq!(contents(&base, "base.txt"), b"base");
+        assert!(base.get_entry_type(&path("dir")).is_err());
+
+        assert_eq!(journal.commit(), AccessorResult::Success);
+        assert_eq!(contents(&base, "base.txt"), b"changed");
+        assert_eq!(contents(&base, "dir/new.txt"), b"new");
+
+        // Nothing is left to roll back once committed
+        assert_eq!(journal.rollback(), AccessorResult::Success);
+        assert_eq!(contents(&journal, "dir/new.txt"), b"new");
+    }
+
+    #[test]
+    fn rollback_discards_pending_changes() {
+        let (base, journal) = journaled();
+
+        assert_eq!(journal.delete_file(&path("base.txt")), AccessorResult::Success);
+        assert_eq!(journal.create_file(&path("new.txt"), 4), AccessorResult::Success);
+        assert!(journal.get_entry_type(&path("base.txt")).is_err());
+
+        assert_eq!(journal.rollback(), AccessorResult::Success);
+        assert_eq!(contents(&journal, "base.txt"), b"base");
+        assert!(journal.get_entry_type(&path("new.txt")).is_err());
+
+        assert_eq!(journal.commit(), AccessorResult::Success);
+        assert!(base.get_entry_type(&path("new.txt")).is_err());
+    }
+
+    #[test]
+    fn rollback_returns_to_provisional_commit() {
+        let (base, journal) = journaled();
+
+        write(&journal, "base.txt", b"first");
+        assert_eq!(journal.commit_provisionally(7), AccessorResult::Success);
+        assert_eq!(journal.provisional_commit_counter(), Some(7));
+
+        write(&journal, "base.txt", b"second");
+        assert_eq!(journal.rollback(), AccessorResult::Success);
+        assert_eq!(contents(&journal, "base.txt"), b"first");
+        assert_eq!(contents(&base, "base.txt"), b"base");
+
+        assert_eq!(journal.commit(), AccessorResult::Success);
+        assert_eq!(journal.provisional_commit_counter(), None);
+        assert_eq!(contents(&base, "base.txt"), b"first");
+    }
+
+    #[test]
+    fn files_are_restricted_to_their_open_mode() {
+        let (_, journal) = journaled();
+        let mut buffer = [0; 4];
+
+        let mut file = journal.open_file(&path("base.txt"), READ).unwrap();
+        assert_eq!(file.write(b"x", 0, false).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.set_size(0).err(), Some(AccessorResult::Unsupported));
+
+        let mut file = journal.open_file(&path("base.txt"), WRITE).unwrap();
+        assert_eq!(file.read(&mut buffer, 0).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.write(b"B", 0, false), Ok(()));
+        assert_eq!(file.write(b"extended", 0, false).err(), Some(AccessorResult::Unsupported));
+
+        let mut file = journal.open_file(&path("base.txt"), WRITE | APPEND).unwrap();
+        assert_eq!(file.write(b"!", 4, false), Ok(()));
+        assert_eq!(contents(&journal, "base.txt"), b"Base!");
+    }
+}
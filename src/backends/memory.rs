@@ -0,0 +1,428 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use super::{ expect_mode, into_result };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FsEntryType, NnPath, OperateRangeId, QueryRangeInfo};
+
+#[derive(Clone)]
+enum Node {
+    /// Shared with every open handle, so writes are visible to all of them and renames don't invalidate them.
+    File(Arc<Mutex<Vec<u8>>>),
+    Directory,
+}
+
+impl Node {
+    fn entry_type(&self) -> FsEntryType {
+        match self {
+            Node::File(_) => FsEntryType::File,
+            Node::Directory => FsEntryType::Directory,
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        match self {
+            Node::File(data) => Arc::strong_count(data) > 1,
+            Node::Directory => false,
+        }
+    }
+}
+
+/// A read/write filesystem kept entirely in memory, starting out empty.
+///
+/// Files that are still open can't be deleted, nor can the directories containing them, which fails with `AccessorResult::AlreadyInUse`. Renaming is fine, open handles follow the file.
+#[derive(Clone)]
+pub struct MemoryFileSystem {
+    entries: Arc<Mutex<BTreeMap<NnPath, Node>>>,
+}
+
+impl Default for MemoryFileSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryFileSystem {
+    pub fn new() -> Self {
+        let mut entries = BTreeMap::new();
+        entries.insert(NnPath::root(), Node::Directory);
+
+        Self {
+            entries: Arc::new(Mutex::new(entries)),
+        }
+    }
+
+    fn expect_type(entries: &BTreeMap<NnPath, Node>, path: &NnPath, ty: FsEntryType) -> Result<(), AccessorResult> {
+        match entries.get(path) {
+            Some(node) if node.entry_type() == ty => Ok(()),
+            _ => Err(AccessorResult::PathNotFound),
+        }
+    }
+
+    /// Check that `path` doesn't exist yet but its parent directory does.
+    fn expect_vacant(entries: &BTreeMap<NnPath, Node>, path: &NnPath) -> Result<(), AccessorResult> {
+        let parent = path.parent().ok_or(AccessorResult::PathAlreadyExists)?;
+
+        Self::expect_type(entries, &parent, FsEntryType::Directory)?;
+
+        if entries.contains_key(path) {
+            Err(AccessorResult::PathAlreadyExists)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn descendants<'a>(entries: &'a BTreeMap<NnPath, Node>, path: &'a NnPath) -> impl Iterator<Item = (&'a NnPath, &'a Node)> {
+        entries.iter().filter(move |(entry, _)| *entry != path && entry.starts_with(path))
+    }
+
+    fn children<'a>(entries: &'a BTreeMap<NnPath, Node>, path: &'a NnPath) -> impl Iterator<Item = (&'a NnPath, &'a Node)> {
+        entries.iter().filter(move |(entry, _)| entry.parent().as_ref() == Some(path))
+    }
+
+    fn expect_closed<'a>(mut nodes: impl Iterator<Item = (&'a NnPath, &'a Node)>) -> Result<(), AccessorResult> {
+        if nodes.any(|(_, node)| node.is_open()) {
+            Err(AccessorResult::AlreadyInUse)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Remove everything under `path`, leaving the directory itself in place.
+    fn clean(entries: &mut BTreeMap<NnPath, Node>, path: &NnPath) -> Result<(), AccessorResult> {
+        Self::expect_closed(Self::descendants(entries, path))?;
+
+        entries.retain(|entry, _| entry == path || !entry.starts_with(path));
+        Ok(())
+    }
+}
+
+impl FileSystemAccessor for MemoryFileSystem {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        let entries = self.entries.lock().unwrap();
+
+        entries.get(path).map(Node::entry_type).ok_or(AccessorResult::PathNotFound)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result(Self::expect_vacant(&entries, path).map(|_| {
+            entries.insert(path.clone(), Node::File(Arc::new(Mutex::new(vec![0; size]))));
+        }))
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let entries = self.entries.lock().unwrap();
+
+        match entries.get(path) {
+            Some(Node::File(data)) => Ok(Box::new(MemoryFile { data: data.clone(), mode })),
+            _ => Err(AccessorResult::PathNotFound),
+        }
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result((|| {
+            Self::expect_type(&entries, path, FsEntryType::File)?;
+
+            if path == new_path {
+                return Ok(());
+            }
+
+            Self::expect_vacant(&entries, new_path)?;
+
+            let node = entries.remove(path).unwrap();
+            entries.insert(new_path.clone(), node);
+            Ok(())
+        })())
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result((|| {
+            Self::expect_type(&entries, path, FsEntryType::File)?;
+            Self::expect_closed(entries.get_key_value(path).into_iter())?;
+
+            entries.remove(path);
+            Ok(())
+        })())
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result(Self::expect_vacant(&entries, path).map(|_| {
+            entries.insert(path.clone(), Node::Directory);
+        }))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let entries = self.entries.lock().unwrap();
+
+        Self::expect_type(&entries, path, FsEntryType::Directory)?;
+
+        let listing = Self::children(&entries, path).map(|(entry, node)| {
+            let ty = match node {
+                Node::File(data) => DirectoryEntryType::File(data.lock().unwrap().len() as i64),
+                Node::Directory => DirectoryEntryType::Directory,
+            };
+
+            DirectoryEntry {
+                path: PathBuf::from(entry.file_name().unwrap()),
+                ty,
+                timestamp: None,
+            }
+        });
+
+        Ok(Box::new(DirectoryListing::new(listing.collect(), mode)))
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result((|| {
+            Self::expect_type(&entries, path, FsEntryType::Directory)?;
+
+            if path == new_path {
+                return Ok(());
+            }
+
+            Self::expect_vacant(&entries, new_path)?;
+
+            if path.is_root() || new_path.starts_with(path) {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            let moved: Vec<NnPath> = entries.keys().filter(|entry| entry.starts_with(path)).cloned().collect();
+
+            for entry in moved {
+                let node = entries.remove(&entry).unwrap();
+                let relative = entry.strip_prefix(path).unwrap();
+
+                entries.insert(new_path.join(relative)?, node);
+            }
+
+            Ok(())
+        })())
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result((|| {
+            Self::expect_type(&entries, path, FsEntryType::Directory)?;
+
+            if path.is_root() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            if Self::children(&entries, path).next().is_some() {
+                return Err(AccessorResult::DirectoryNotEmpty);
+            }
+
+            entries.remove(path);
+            Ok(())
+        })())
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result((|| {
+            Self::expect_type(&entries, path, FsEntryType::Directory)?;
+
+            if path.is_root() {
+                return Err(AccessorResult::Unsupported);
+            }
+
+            Self::clean(&mut entries, path)?;
+            entries.remove(path);
+            Ok(())
+        })())
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        let mut entries = self.entries.lock().unwrap();
+
+        into_result(Self::expect_type(&entries, path, FsEntryType::Directory).and_then(|_| Self::clean(&mut entries, path)))
+    }
+}
+
+/// A file opened through a `MemoryFileSystem`, restricted to the mode it was opened with.
+struct MemoryFile {
+    data: Arc<Mutex<Vec<u8>>>,
+    mode: nn::fs::OpenMode,
+}
+
+impl FileAccessor for MemoryFile {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Read)?;
+
+        let data = self.data.lock().unwrap();
+        let data = data.get(offset..).unwrap_or(&[]);
+        let size = data.len().min(buffer.len());
+
+        buffer[..size].copy_from_slice(&data[..size]);
+        Ok(size)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Write)?;
+
+        let end = offset.checked_add(data.len()).ok_or(AccessorResult::Unexpected)?;
+        let mut contents = self.data.lock().unwrap();
+
+        // Writing past the end zero-fills the gap
+        if contents.len() < end {
+            expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Append)?;
+            contents.resize(end, 0);
+        }
+
+        contents[offset..end].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Write)?;
+
+        self.data.lock().unwrap().resize(new_size, 0);
+        Ok(())
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.data.lock().unwrap().len())
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        AccessorResult::Success
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        match operation {
+            OperateRangeId::FillZero => {
+                if let Err(e) = expect_mode(self.mode, nn::fs::OpenMode_OpenMode_Write) {
+                    return e;
+                }
+
+                let mut contents = self.data.lock().unwrap();
+                let end = offset.saturating_add(size).min(contents.len());
+
+                if offset < end {
+                    contents[offset..end].iter_mut().for_each(|byte| *byte = 0);
+                }
+
+                AccessorResult::Success
+            },
+            OperateRangeId::Invalidate | OperateRangeId::QueryRange => AccessorResult::Success,
+            OperateRangeId::DestroySignature => AccessorResult::Unsupported,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::accessors::read_all_entries;
+    use crate::backends::{ path, APPEND, READ, WRITE };
+
+    #[test]
+    fn files_are_restricted_to_their_open_mode() {
+        let fs = MemoryFileSystem::new();
+        let mut buffer = [0; 8];
+
+        assert_eq!(fs.create_file(&path("file"), 4), AccessorResult::Success);
+
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+        assert_eq!(file.read(&mut buffer, 0), Ok(4));
+        assert_eq!(file.write(b"data", 0, false).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.set_size(0).err(), Some(AccessorResult::Unsupported));
+
+        let mut file = fs.open_file(&path("file"), WRITE).unwrap();
+        assert_eq!(file.read(&mut buffer, 0).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.write(b"data", 0, false), Ok(()));
+        assert_eq!(file.write(b"more", 2, false).err(), Some(AccessorResult::Unsupported));
+        assert_eq!(file.set_size(8), Ok(()));
+
+        let mut file = fs.open_file(&path("file"), WRITE | APPEND).unwrap();
+        assert_eq!(file.write(b"appended", 8, false), Ok(()));
+        assert_eq!(file.get_size(), Ok(16));
+    }
+
+    #[test]
+    fn writes_past_the_end_zero_fill_the_gap() {
+        let fs = MemoryFileSystem::new();
+        let mut buffer = [0xff; 10];
+
+        assert_eq!(fs.create_file(&path("file"), 2), AccessorResult::Success);
+
+        let mut file = fs.open_file(&path("file"), READ | WRITE | APPEND).unwrap();
+        assert_eq!(file.write(b"end", 7, false), Ok(()));
+        assert_eq!(file.read(&mut buffer, 0), Ok(10));
+        assert_eq!(&buffer, b"\0\0\0\0\0\0\0end");
+
+        assert_eq!(file.write(b"x", usize::MAX, false).err(), Some(AccessorResult::Unexpected));
+        assert_eq!(file.get_size(), Ok(10));
+    }
+
+    #[test]
+    fn existing_paths_cant_be_created_again() {
+        let fs = MemoryFileSystem::new();
+
+        assert_eq!(fs.create_file(&path("file"), 0), AccessorResult::Success);
+        assert_eq!(fs.create_directory(&path("dir")), AccessorResult::Success);
+
+        assert_eq!(fs.create_file(&path("file"), 4), AccessorResult::PathAlreadyExists);
+        assert_eq!(fs.create_file(&path("dir"), 0), AccessorResult::PathAlreadyExists);
+        assert_eq!(fs.create_directory(&path("file")), AccessorResult::PathAlreadyExists);
+        assert_eq!(fs.create_file(&path("missing/file"), 0), AccessorResult::PathNotFound);
+
+        // The existing file is left untouched
+        assert_eq!(fs.open_file(&path("file"), READ).unwrap().get_size(), Ok(0));
+    }
+
+    #[test]
+    fn open_files_block_deletion_but_follow_renames() {
+        let fs = MemoryFileSystem::new();
+
+        assert_eq!(fs.create_directory(&path("dir")), AccessorResult::Success);
+        assert_eq!(fs.create_file(&path("dir/file"), 0), AccessorResult::Success);
+
+        let mut file = fs.open_file(&path("dir/file"), WRITE | APPEND).unwrap();
+        assert_eq!(fs.delete_file(&path("dir/file")), AccessorResult::AlreadyInUse);
+        assert_eq!(fs.delete_directory_recursively(&path("dir")), AccessorResult::AlreadyInUse);
+
+        assert_eq!(fs.rename_directory(&path("dir"), &path("moved")), AccessorResult::Success);
+        assert_eq!(file.write(b"data", 0, false), Ok(()));
+        drop(file);
+
+        let mut file = fs.open_file(&path("moved/file"), READ).unwrap();
+        assert_eq!(file.get_size(), Ok(4));
+        drop(file);
+
+        assert_eq!(fs.delete_directory(&path("moved")), AccessorResult::DirectoryNotEmpty);
+        assert_eq!(fs.delete_directory_recursively(&path("moved")), AccessorResult::Success);
+        assert!(fs.get_entry_type(&path("moved/file")).is_err());
+    }
+
+    #[test]
+    fn listings_filter_by_mode() {
+        let fs = MemoryFileSystem::new();
+
+        assert_eq!(fs.create_directory(&path("dir")), AccessorResult::Success);
+        assert_eq!(fs.create_file(&path("file"), 3), AccessorResult::Success);
+        assert_eq!(fs.create_file(&path("dir/nested"), 0), AccessorResult::Success);
+
+        let entries = read_all_entries(&mut *fs.open_directory(&NnPath::root(), nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).unwrap()).unwrap();
+        assert_eq!(entries.len(), 2);
+
+        let entries = read_all_entries(&mut *fs.open_directory(&NnPath::root(), nn::fs::OpenDirectoryMode_OpenDirectoryMode_File).unwrap()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path.to_str(), Some("file"));
+        assert!(matches!(entries[0].ty, DirectoryEntryType::File(3)));
+    }
+}
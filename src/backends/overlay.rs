@@ -0,0 +1,172 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use skyline::nn;
+
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, QueryEntryId};
+
+/// Stacks several read-only `FileSystemAccessor`s on top of each other.
+///
+/// A path resolves to the layer with the highest priority that contains it, layers added later win over earlier ones with the same priority.
+/// Directories are merged across every layer they exist in, unless a higher layer has a file with the same path.
+#[derive(Default)]
+pub struct OverlayFileSystem {
+    /// Sorted from the highest priority to the lowest
+    layers: Vec<(i32, Box<dyn FileSystemAccessor>)>,
+}
+
+impl OverlayFileSystem {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_layer<A: FileSystemAccessor + 'static>(&mut self, priority: i32, layer: A) -> &mut Self {
+        let idx = self.layers.iter().position(|(layer_priority, _)| *layer_priority <= priority).unwrap_or(self.layers.len());
+
+        self.layers.insert(idx, (priority, Box::new(layer)));
+        self
+    }
+
+    pub fn with_layer<A: FileSystemAccessor + 'static>(mut self, priority: i32, layer: A) -> Self {
+        self.add_layer(priority, layer);
+        self
+    }
+
+    fn layers(&self) -> impl Iterator<Item = &dyn FileSystemAccessor> {
+        self.layers.iter().map(|(_, layer)| &**layer)
+    }
+
+    /// Whether `layer`, which doesn't contain `path`, has a file in place of one of its ancestors.
+    fn hides_lower_layers(layer: &dyn FileSystemAccessor, path: &NnPath) -> bool {
+        let closest = path.ancestors().skip(1).find_map(|ancestor| layer.get_entry_type(&ancestor).ok());
+
+        closest == Some(FsEntryType::File)
+    }
+
+    /// The highest layer containing `path`, along with the type of the entry there.
+    fn resolve(&self, path: &NnPath) -> Result<(&dyn FileSystemAccessor, FsEntryType), AccessorResult> {
+        for layer in self.layers() {
+            if let Ok(ty) = layer.get_entry_type(path) {
+                return Ok((layer, ty));
+            }
+
+            if Self::hides_lower_layers(layer, path) {
+                break;
+            }
+        }
+
+        Err(AccessorResult::PathNotFound)
+    }
+
+    fn resolve_file(&self, path: &NnPath) -> Result<&dyn FileSystemAccessor, AccessorResult> {
+        match self.resolve(path)? {
+            (layer, FsEntryType::File) => Ok(layer),
+            _ => Err(AccessorResult::PathNotFound),
+        }
+    }
+}
+
+impl FileSystemAccessor for OverlayFileSystem {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.resolve(path).map(|(_, ty)| ty)
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        self.resolve_file(path)?.open_file(path, mode)
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        if self.resolve(path)?.1 != FsEntryType::Directory {
+            return Err(AccessorResult::PathNotFound);
+        }
+
+        let mut entries = BTreeMap::new();
+
+        for layer in self.layers() {
+            match layer.get_entry_type(path) {
+                Ok(FsEntryType::Directory) => (),
+                // A file hides the directories of the layers below it
+                Ok(FsEntryType::File) => break,
+                Err(_) if Self::hides_lower_layers(layer, path) => break,
+                Err(_) => continue,
+            }
+
+            let mut directory = layer.open_directory(path, nn::fs::OpenDirectoryMode_OpenDirectoryMode_All)?;
+
+            for entry in crate::accessors::read_all_entries(&mut *directory)? {
+                if let Some(name) = entry.path.file_name().and_then(|name| name.to_str()) {
+                    entries.entry(name.to_owned()).or_insert((entry.ty, entry.timestamp));
+                }
+            }
+        }
+
+        let entries = entries.into_iter().map(|(name, (ty, timestamp))| DirectoryEntry { path: PathBuf::from(name), ty, timestamp }).collect();
+
+        Ok(Box::new(DirectoryListing::new(entries, mode)))
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        self.resolve_file(path)?.get_file_time_stamp(path)
+    }
+
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        match self.resolve(path) {
+            Ok((layer, _)) => layer.query_entry(output, input, query_id, path),
+            Err(e) => e,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_with, path, read_path };
+
+    #[test]
+    fn higher_priority_layers_win() {
+        let overlay = OverlayFileSystem::new()
+            .with_layer(10, memory_with(&[("file", b"high")]))
+            .with_layer(0, memory_with(&[("file", b"low"), ("only_low", b"low")]));
+
+        assert_eq!(read_path(&overlay, "file").unwrap(), b"high");
+        assert_eq!(read_path(&overlay, "only_low").unwrap(), b"low");
+        assert_eq!(read_path(&overlay, "missing").err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn later_layers_win_ties() {
+        let overlay = OverlayFileSystem::new()
+            .with_layer(0, memory_with(&[("file", b"first")]))
+            .with_layer(0, memory_with(&[("file", b"second")]));
+
+        assert_eq!(read_path(&overlay, "file").unwrap(), b"second");
+    }
+
+    #[test]
+    fn files_hide_lower_directories() {
+        let overlay = OverlayFileSystem::new()
+            .with_layer(0, memory_with(&[("dir/nested", b"nested"), ("other/file", b"other")]))
+            .with_layer(1, memory_with(&[("dir", b"file")]));
+
+        assert_eq!(overlay.get_entry_type(&path("dir")), Ok(FsEntryType::File));
+        assert_eq!(overlay.get_entry_type(&path("dir/nested")), Err(AccessorResult::PathNotFound));
+        assert_eq!(read_path(&overlay, "dir/nested").err(), Some(AccessorResult::PathNotFound));
+        assert_eq!(list_path(&overlay, "dir").err(), Some(AccessorResult::PathNotFound));
+        assert_eq!(read_path(&overlay, "other/file").unwrap(), b"other");
+    }
+
+    #[test]
+    fn directories_are_merged() {
+        let overlay = OverlayFileSystem::new()
+            .with_layer(0, memory_with(&[("dir/a", b"base"), ("dir/b", b"b")]))
+            .with_layer(1, memory_with(&[("dir/a", b"modded"), ("dir/c", b"c")]));
+
+        let entries = list_path(&overlay, "dir").unwrap();
+
+        assert_eq!(entries, vec![
+            ("a".to_owned(), Some(6)),
+            ("b".to_owned(), Some(1)),
+            ("c".to_owned(), Some(1)),
+        ]);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use super::host_directory::to_accessor_result;
+use super::{ read_exact, ArchiveFile, HostFile };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FsEntryType, NnPath};
+
+const HEADER_SIZE: usize = 0x10;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Flavor of partition filesystem, which only differ by the size of their file entries.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartitionFormat {
+    /// Used by NSPs and ExeFS
+    Pfs0,
+    /// Used by gamecard images, its entries also carry a hash of the start of each file
+    Hfs0,
+}
+
+impl PartitionFormat {
+    fn entry_size(self) -> usize {
+        match self {
+            PartitionFormat::Pfs0 => 0x18,
+            PartitionFormat::Hfs0 => 0x40,
+        }
+    }
+}
+
+/// A read-only view of a PFS0 or HFS0 partition, which holds a flat list of files.
+pub struct PartitionFileSystem<F: FileAccessor> {
+    archive: Arc<Mutex<F>>,
+    format: PartitionFormat,
+    /// Offset and size of each file in the archive
+    files: BTreeMap<String, (usize, usize)>,
+}
+
+impl PartitionFileSystem<HostFile> {
+    /// Open the partition at `path` on the host filesystem.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, AccessorResult> {
+        Self::new(HostFile::open(path).map_err(to_accessor_result)?)
+    }
+}
+
+impl<F: FileAccessor> PartitionFileSystem<F> {
+    pub fn new(mut archive: F) -> Result<Self, AccessorResult> {
+        let mut header = [0; HEADER_SIZE];
+        read_exact(&mut archive, &mut header, 0)?;
+
+        let format = match &header[..4] {
+            b"PFS0" => PartitionFormat::Pfs0,
+            b"HFS0" => PartitionFormat::Hfs0,
+            _ => return Err(AccessorResult::Unexpected),
+        };
+
+        let archive_size = archive.get_size()?;
+        let entry_count = u32_at(&header, 4) as usize;
+        let string_table_size = u32_at(&header, 8) as usize;
+
+        // The entries are followed by the string table, then by the data of the files. Both sizes come from the header, check them before allocating
+        let entries_size = entry_count.checked_mul(format.entry_size()).ok_or(AccessorResult::Unexpected)?;
+        let table_size = entries_size.checked_add(string_table_size).ok_or(AccessorResult::Unexpected)?;
+
+        if table_size > archive_size.checked_sub(HEADER_SIZE).ok_or(AccessorResult::Unexpected)? {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let mut table = vec![0; table_size];
+        read_exact(&mut archive, &mut table, HEADER_SIZE)?;
+
+        let (entries, strings) = table.split_at(entries_size);
+        let data_offset = HEADER_SIZE + table.len();
+        let mut files = BTreeMap::new();
+
+        for entry in entries.chunks_exact(format.entry_size()) {
+            let name = strings.get(u32_at(entry, 0x10) as usize..).ok_or(AccessorResult::Unexpected)?;
+            let name = &name[..name.iter().position(|&byte| byte == 0).unwrap_or(name.len())];
+
+            let offset = data_offset.checked_add(u64_at(entry, 0) as usize).ok_or(AccessorResult::Unexpected)?;
+            let size = u64_at(entry, 8) as usize;
+
+            match offset.checked_add(size) {
+                Some(end) if end <= archive_size => (),
+                _ => return Err(AccessorResult::Unexpected),
+            }
+
+            files.insert(String::from_utf8_lossy(name).into_owned(), (offset, size));
+        }
+
+        Ok(Self {
+            archive: Arc::new(Mutex::new(archive)),
+            format,
+            files,
+        })
+    }
+
+    pub fn format(&self) -> PartitionFormat {
+        self.format
+    }
+
+    fn file(&self, path: &NnPath) -> Result<(usize, usize), AccessorResult> {
+        self.files.get(path.as_str()).copied().ok_or(AccessorResult::PathNotFound)
+    }
+}
+
+impl<F: FileAccessor + 'static> FileSystemAccessor for PartitionFileSystem<F> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        if path.is_root() {
+            Ok(FsEntryType::Directory)
+        } else {
+            self.file(path).map(|_| FsEntryType::File)
+        }
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let (offset, size) = self.file(path)?;
+
+        Ok(Box::new(ArchiveFile::new(self.archive.clone(), offset, size)))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        if !path.is_root() {
+            return Err(AccessorResult::PathNotFound);
+        }
+
+        let entries = self.files.iter().map(|(name, (_, size))| DirectoryEntry {
+            path: PathBuf::from(name),
+            ty: DirectoryEntryType::File(*size as i64),
+            timestamp: None,
+        });
+
+        Ok(Box::new(DirectoryListing::new(entries.collect(), mode)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_file, path, read_path };
+
+    fn build_partition(format: PartitionFormat, files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut entries = Vec::new();
+        let mut strings = Vec::new();
+        let mut data = Vec::new();
+
+        for (name, contents) in files {
+            let mut entry = vec![0; format.entry_size()];
+
+            entry[..8].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            entry[8..0x10].copy_from_slice(&(contents.len() as u64).to_le_bytes());
+            entry[0x10..0x14].copy_from_slice(&(strings.len() as u32).to_le_bytes());
+            entries.extend_from_slice(&entry);
+
+            strings.extend_from_slice(name.as_bytes());
+            strings.push(0);
+            data.extend_from_slice(contents);
+        }
+
+        // The string table is padded, as it is in real partitions
+        strings.resize((strings.len() + 0x1f) & !0x1f, 0);
+
+        let mut archive = match format {
+            PartitionFormat::Pfs0 => b"PFS0".to_vec(),
+            PartitionFormat::Hfs0 => b"HFS0".to_vec(),
+        };
+
+        archive.extend_from_slice(&(files.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&(strings.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&entries);
+        archive.extend_from_slice(&strings);
+        archive.extend_from_slice(&data);
+        archive
+    }
+
+    #[test]
+    fn reads_both_formats() {
+        for &format in &[PartitionFormat::Pfs0, PartitionFormat::Hfs0] {
+            let archive = build_partition(format, &[("main", b"code"), ("main.npdm", b"meta"), ("empty", b"")]);
+            let partition = PartitionFileSystem::new(memory_file(&archive)).unwrap();
+
+            assert_eq!(partition.format(), format);
+            assert_eq!(read_path(&partition, "main"), Ok(b"code".to_vec()));
+            assert_eq!(read_path(&partition, "main.npdm"), Ok(b"meta".to_vec()));
+            assert_eq!(read_path(&partition, "empty"), Ok(Vec::new()));
+            assert_eq!(partition.get_entry_type(&path("")), Ok(FsEntryType::Directory));
+            assert_eq!(partition.get_entry_type(&path("main")), Ok(FsEntryType::File));
+            assert_eq!(partition.get_entry_type(&path("missing")), Err(AccessorResult::PathNotFound));
+        }
+    }
+
+    #[test]
+    fn lists_the_files_at_the_root() {
+        let archive = build_partition(PartitionFormat::Pfs0, &[("b", b"bb"), ("a", b"a")]);
+        let partition = PartitionFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(list_path(&partition, ""), Ok(vec![("a".to_string(), Some(1)), ("b".to_string(), Some(2))]));
+        assert_eq!(list_path(&partition, "a").err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn malformed_partitions_are_rejected() {
+        let archive = build_partition(PartitionFormat::Hfs0, &[("file", b"data")]);
+
+        assert!(PartitionFileSystem::new(memory_file(b"NCA3")).is_err());
+        assert!(PartitionFileSystem::new(memory_file(&archive[..HEADER_SIZE + 0x20])).is_err());
+
+        let mut unknown = archive.clone();
+        unknown[..4].copy_from_slice(b"PFS1");
+        assert!(PartitionFileSystem::new(memory_file(&unknown)).is_err());
+
+        // Name past the end of the string table
+        let mut misnamed = archive.clone();
+        misnamed[HEADER_SIZE + 0x10..HEADER_SIZE + 0x14].copy_from_slice(&0x100u32.to_le_bytes());
+        assert!(PartitionFileSystem::new(memory_file(&misnamed)).is_err());
+
+        // Tables larger than the archive, which must be refused before allocating them
+        let mut crowded = archive.clone();
+        crowded[4..8].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(PartitionFileSystem::new(memory_file(&crowded)).err(), Some(AccessorResult::Unexpected));
+
+        let mut wordy = archive.clone();
+        wordy[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(PartitionFileSystem::new(memory_file(&wordy)).err(), Some(AccessorResult::Unexpected));
+
+        // File data past the end of the archive, or wrapping around
+        let mut misplaced = archive.clone();
+        misplaced[HEADER_SIZE..HEADER_SIZE + 8].copy_from_slice(&(archive.len() as u64).to_le_bytes());
+        assert_eq!(PartitionFileSystem::new(memory_file(&misplaced)).err(), Some(AccessorResult::Unexpected));
+
+        let mut oversized = archive;
+        oversized[HEADER_SIZE + 8..HEADER_SIZE + 0x10].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert_eq!(PartitionFileSystem::new(memory_file(&oversized)).err(), Some(AccessorResult::Unexpected));
+    }
+}
@@ -0,0 +1,632 @@
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use skyline::nn;
+
+use super::{ is_writable, read_to_end };
+use crate::{AccessorResult, DirectoryAccessor, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath, OperateRangeId, QueryEntryId, QueryRangeInfo};
+
+const DEFAULT_WINDOW: usize = 0x40000;
+
+/// Reads that have to follow each other before the next window gets fetched
+const SEQUENTIAL_READS: usize = 2;
+
+/// Read up to `size` bytes from `offset`, stopping short at the end of the file.
+fn read_window(file: &mut dyn FileAccessor, offset: usize, size: usize) -> Result<Vec<u8>, AccessorResult> {
+    let mut data = vec![0; size];
+    let mut filled = 0;
+
+    while filled < size {
+        match file.read(&mut data[filled..], offset + filled)? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+
+    data.truncate(filled);
+    Ok(data)
+}
+
+/// Window to read, identified so answers to requests that were given up on can be told apart
+struct Request {
+    id: u64,
+    offset: usize,
+    size: usize,
+}
+
+/// Thread fetching the windows of a single file, through a handle it opens on the first request and keeps until the file is closed.
+struct Worker {
+    requests: Sender<Request>,
+    responses: Receiver<(u64, Result<Vec<u8>, AccessorResult>)>,
+    thread: Option<JoinHandle<()>>,
+    next_id: u64,
+}
+
+impl Worker {
+    fn spawn<A: FileSystemAccessor + Send + Sync + 'static>(fs: Arc<A>, path: NnPath) -> Self {
+        let (requests, pending) = mpsc::channel::<Request>();
+        let (done, responses) = mpsc::channel();
+
+        // Accessors aren't Send, so the handle has to be opened on the thread using it. The loop ends once the file drops its end of the channel.
+        let thread = thread::spawn(move || {
+            let mut file = None;
+
+            for request in pending {
+                if file.is_none() {
+                    file = match fs.open_file(&path, nn::fs::OpenMode_OpenMode_Read) {
+                        Ok(file) => Some(file),
+                        Err(e) => {
+                            let _ = done.send((request.id, Err(e)));
+                            continue;
+                        },
+                    };
+                }
+
+                let result = read_window(&mut **file.as_mut().unwrap(), request.offset, request.size);
+
+                if done.send((request.id, result)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            requests,
+            responses,
+            thread: Some(thread),
+            next_id: 0,
+        }
+    }
+
+    fn request(&mut self, offset: usize, size: usize) -> u64 {
+        self.next_id += 1;
+
+        // A worker that went away is noticed when waiting for the answer
+        let _ = self.requests.send(Request { id: self.next_id, offset, size });
+        self.next_id
+    }
+
+    /// Wait for the answer to request `id`, skipping the ones to requests made before it.
+    fn wait(&self, id: u64) -> Result<Vec<u8>, AccessorResult> {
+        loop {
+            match self.responses.recv() {
+                Ok((answered, result)) if answered == id => return result,
+                Ok(_) => continue,
+                Err(_) => return Err(AccessorResult::Unexpected),
+            }
+        }
+    }
+}
+
+impl Drop for Worker {
+    /// Wait for the thread to close its handle, so the file is really closed once the one reading it is.
+    fn drop(&mut self) {
+        drop(mem::replace(&mut self.requests, mpsc::channel().0));
+
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[derive(Default)]
+struct Prefetched {
+    files: BTreeMap<NnPath, Arc<[u8]>>,
+    /// Bumped by every eviction, so files read while it happened aren't kept
+    generation: u64,
+}
+
+impl Prefetched {
+    fn evict(&mut self, filter: impl Fn(&NnPath) -> bool) {
+        self.files.retain(|path, _| !filter(path));
+        self.generation += 1;
+    }
+}
+
+/// Wraps a `FileSystemAccessor` and reads ahead of files being read sequentially, fetching the next window in the background.
+///
+/// Each file read sequentially gets a worker thread of its own, started on the first window and reusing a single handle until the file is closed.
+/// `prefetch` loads whole files into memory ahead of time, they are then served from there when opened read-only until they're modified through the wrapper.
+///
+/// The wrapped filesystem is shared with those threads, so it has to be `Send + Sync`. Filesystems holding boxed accessors, such as `OverlayFileSystem`
+/// or a `ZipFileSystem` over a `Box<dyn FileAccessor>`, aren't and can't be wrapped, wrap the filesystems they are made of instead.
+pub struct ReadAheadFileSystem<A: FileSystemAccessor + Send + Sync + 'static> {
+    inner: Arc<A>,
+    window: usize,
+    prefetched: Arc<Mutex<Prefetched>>,
+}
+
+impl<A: FileSystemAccessor + Send + Sync + 'static> ReadAheadFileSystem<A> {
+    /// Read ahead by windows of 256 KiB.
+    pub fn new(inner: A) -> Self {
+        Self::with_window(inner, DEFAULT_WINDOW)
+    }
+
+    pub fn with_window(inner: A, window: usize) -> Self {
+        Self {
+            inner: Arc::new(inner),
+            window: window.max(1),
+            prefetched: Arc::new(Mutex::new(Prefetched::default())),
+        }
+    }
+
+    pub fn inner(&self) -> &A {
+        &self.inner
+    }
+
+    /// Load the files at `paths` into memory on a background thread. Files that can't be read are skipped.
+    pub fn prefetch<I: IntoIterator<Item = NnPath>>(&self, paths: I) -> JoinHandle<()> {
+        let inner = self.inner.clone();
+        let prefetched = self.prefetched.clone();
+        let paths: Vec<NnPath> = paths.into_iter().collect();
+
+        thread::spawn(move || {
+            for path in paths {
+                let generation = prefetched.lock().unwrap().generation;
+                let data = match inner.open_file(&path, nn::fs::OpenMode_OpenMode_Read).and_then(|mut file| read_to_end(&mut *file)) {
+                    Ok(data) => data,
+                    Err(_) => continue,
+                };
+
+                let mut prefetched = prefetched.lock().unwrap();
+
+                if prefetched.generation == generation {
+                    prefetched.files.insert(path, data.into());
+                }
+            }
+        })
+    }
+
+    /// Release the memory held by every prefetched file.
+    pub fn clear_prefetched(&self) {
+        self.prefetched.lock().unwrap().evict(|_| true);
+    }
+
+    /// Drop the prefetched files matching `filter` once a mutation of the wrapped filesystem is done, passing its result through.
+    fn evict_after(&self, result: AccessorResult, filter: impl Fn(&NnPath) -> bool) -> AccessorResult {
+        self.prefetched.lock().unwrap().evict(filter);
+        result
+    }
+}
+
+impl<A: FileSystemAccessor + Send + Sync + 'static> FileSystemAccessor for ReadAheadFileSystem<A> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.inner.get_entry_type(path)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.evict_after(self.inner.create_file(path, size), |file| file == path)
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        if is_writable(mode) {
+            self.prefetched.lock().unwrap().evict(|file| file == path);
+        } else if let Some(data) = self.prefetched.lock().unwrap().files.get(path) {
+            return Ok(Box::new(PrefetchedFile {
+                data: data.clone(),
+                path: path.clone(),
+                prefetched: self.prefetched.clone(),
+            }));
+        }
+
+        Ok(Box::new(ReadAheadFile {
+            file: self.inner.open_file(path, mode)?,
+            fs: self.inner.clone(),
+            path: path.clone(),
+            prefetched: self.prefetched.clone(),
+            window: self.window,
+            worker: None,
+            next_offset: 0,
+            sequential_reads: 0,
+            ahead: None,
+            pending: None,
+            reached_end: false,
+        }))
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.evict_after(self.inner.rename_file(path, new_path), |file| file == path || file == new_path)
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        self.evict_after(self.inner.delete_file(path), |file| file == path)
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        self.inner.create_directory(path)
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        self.inner.open_directory(path, mode)
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.evict_after(self.inner.rename_directory(path, new_path), |file| file.starts_with(path) || file.starts_with(new_path))
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        self.inner.delete_directory(path)
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.evict_after(self.inner.delete_directory_recursively(path), |file| file.starts_with(path))
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.evict_after(self.inner.clean_directory_recursively(path), |file| file.starts_with(path))
+    }
+
+    fn get_free_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.inner.get_free_space_size(path)
+    }
+
+    fn get_total_space_size(&self, path: &NnPath) -> Result<usize, AccessorResult> {
+        self.inner.get_total_space_size(path)
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        self.inner.get_file_time_stamp(path)
+    }
+
+    fn query_entry(&self, output: &mut [u8], input: &[u8], query_id: QueryEntryId, path: &NnPath) -> AccessorResult {
+        self.inner.query_entry(output, input, query_id, path)
+    }
+
+    fn commit(&self) -> AccessorResult {
+        self.inner.commit()
+    }
+
+    fn commit_provisionally(&self, counter: u64) -> AccessorResult {
+        self.inner.commit_provisionally(counter)
+    }
+
+    fn rollback(&self) -> AccessorResult {
+        self.evict_after(self.inner.rollback(), |_| true)
+    }
+
+    fn check_free_space(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.inner.check_free_space(path, size)
+    }
+}
+
+/// A file served from the memory of a `ReadAheadFileSystem::prefetch`.
+struct PrefetchedFile {
+    data: Arc<[u8]>,
+    path: NnPath,
+    prefetched: Arc<Mutex<Prefetched>>,
+}
+
+impl FileAccessor for PrefetchedFile {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        if offset >= self.data.len() {
+            return Ok(0);
+        }
+
+        let size = buffer.len().min(self.data.len() - offset);
+
+        buffer[..size].copy_from_slice(&self.data[offset..offset + size]);
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.data.len())
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        AccessorResult::Success
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        match operation {
+            // The next open goes back to the wrapped filesystem, this file keeps what it has
+            OperateRangeId::Invalidate => {
+                let path = &self.path;
+
+                self.prefetched.lock().unwrap().evict(|file| file == path);
+                AccessorResult::Success
+            },
+            OperateRangeId::QueryRange => AccessorResult::Success,
+            _ => AccessorResult::Unsupported,
+        }
+    }
+}
+
+/// A file opened through a `ReadAheadFileSystem`.
+struct ReadAheadFile<A: FileSystemAccessor + Send + Sync + 'static> {
+    file: Box<dyn FileAccessor>,
+    fs: Arc<A>,
+    path: NnPath,
+    prefetched: Arc<Mutex<Prefetched>>,
+    window: usize,
+    /// Started on the first window fetched
+    worker: Option<Worker>,
+    /// Where the next read has to start to be sequential
+    next_offset: usize,
+    sequential_reads: usize,
+    /// Data already fetched and its offset in the file
+    ahead: Option<(usize, Vec<u8>)>,
+    /// Offset in the file of the window being fetched by the worker, and the ID of the request
+    pending: Option<(usize, u64)>,
+    /// Set once a window came back short, as there's nothing left to fetch after it
+    reached_end: bool,
+}
+
+impl<A: FileSystemAccessor + Send + Sync + 'static> ReadAheadFile<A> {
+    /// Forget what was read ahead, the answer to a window still being fetched is skipped once it comes.
+    fn discard(&mut self) {
+        self.ahead = None;
+        self.pending = None;
+        self.reached_end = false;
+    }
+
+    /// Forget what was read ahead before the file changes, along with its prefetched copy.
+    fn invalidate(&mut self) {
+        self.discard();
+
+        let path = &self.path;
+        self.prefetched.lock().unwrap().evict(|file| file == path);
+    }
+
+    /// Wait for the window being fetched and append it to what was already read ahead.
+    fn collect(&mut self) -> Result<(), AccessorResult> {
+        let (start, id) = match self.pending.take() {
+            Some(pending) => pending,
+            None => return Ok(()),
+        };
+
+        let data = self.worker.as_ref().ok_or(AccessorResult::Unexpected)?.wait(id)?;
+
+        self.reached_end = data.len() < self.window;
+
+        match &mut self.ahead {
+            Some((ahead_start, ahead)) if *ahead_start + ahead.len() == start => ahead.extend_from_slice(&data),
+            _ => self.ahead = Some((start, data)),
+        }
+
+        Ok(())
+    }
+
+    fn fetch(&mut self, start: usize) {
+        if self.worker.is_none() {
+            self.worker = Some(Worker::spawn(self.fs.clone(), self.path.clone()));
+        }
+
+        let id = self.worker.as_mut().unwrap().request(start, self.window);
+
+        self.pending = Some((start, id));
+    }
+}
+
+impl<A: FileSystemAccessor + Send + Sync + 'static> FileAccessor for ReadAheadFile<A> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        if offset == self.next_offset {
+            self.sequential_reads += 1;
+        } else {
+            self.sequential_reads = 0;
+            self.discard();
+        }
+
+        if matches!(self.pending, Some((start, _)) if start < offset + buffer.len()) {
+            // A failed fetch only loses the read-ahead, the read itself goes to the file directly
+            if self.collect().is_err() {
+                self.discard();
+            }
+        }
+
+        let mut size = 0;
+
+        if let Some((start, ahead)) = &mut self.ahead {
+            if *start <= offset && offset < *start + ahead.len() {
+                let from = offset - *start;
+
+                size = buffer.len().min(ahead.len() - from);
+                buffer[..size].copy_from_slice(&ahead[from..from + size]);
+            }
+
+            // Only keep what hasn't been read yet
+            let consumed = (offset + size).saturating_sub(*start).min(ahead.len());
+
+            ahead.drain(..consumed);
+            *start += consumed;
+        }
+
+        if size < buffer.len() {
+            size += self.file.read(&mut buffer[size..], offset + size)?;
+        }
+
+        self.next_offset = offset + size;
+
+        if self.sequential_reads >= SEQUENTIAL_READS && self.pending.is_none() && size == buffer.len() {
+            let start = match &self.ahead {
+                Some((start, ahead)) if *start == self.next_offset => start + ahead.len(),
+                _ => self.next_offset,
+            };
+
+            // Stay a single window ahead of the reader
+            if start - self.next_offset < self.window && !self.reached_end {
+                self.fetch(start);
+            }
+        }
+
+        Ok(size)
+    }
+
+    fn write(&mut self, data: &[u8], offset: usize, should_append: bool) -> Result<(), AccessorResult> {
+        self.invalidate();
+        self.file.write(data, offset, should_append)
+    }
+
+    fn set_size(&mut self, new_size: usize) -> Result<(), AccessorResult> {
+        self.invalidate();
+        self.file.set_size(new_size)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        self.file.get_size()
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        self.file.flush()
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        if operation == OperateRangeId::Invalidate {
+            self.invalidate();
+        }
+
+        self.file.operate_range(operation, offset, size, info)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use crate::backends::{ memory_with, overwrite, path, read_at, read_path, MemoryFileSystem, READ, WRITE };
+
+    const WINDOW: usize = 256;
+
+    fn contents() -> Vec<u8> {
+        (0..4096u32).map(|idx| (idx % 253) as u8).collect()
+    }
+
+    /// Counts the files opened through it.
+    struct CountingFileSystem {
+        inner: MemoryFileSystem,
+        opens: Arc<AtomicUsize>,
+    }
+
+    impl FileSystemAccessor for CountingFileSystem {
+        fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+            self.inner.get_entry_type(path)
+        }
+
+        fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+            self.opens.fetch_add(1, Ordering::SeqCst);
+            self.inner.open_file(path, mode)
+        }
+
+        fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+            self.inner.open_directory(path, mode)
+        }
+    }
+
+    fn counting(files: &[(&str, &[u8])]) -> (ReadAheadFileSystem<CountingFileSystem>, Arc<AtomicUsize>) {
+        let opens = Arc::new(AtomicUsize::new(0));
+        let fs = ReadAheadFileSystem::with_window(CountingFileSystem { inner: memory_with(files), opens: opens.clone() }, WINDOW);
+
+        (fs, opens)
+    }
+
+    #[test]
+    fn sequential_reads_share_one_worker_handle() {
+        let (fs, opens) = counting(&[("file", &contents())]);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+        let mut data = Vec::new();
+
+        while data.len() < 4096 {
+            data.extend_from_slice(&read_at(&mut *file, data.len(), 100));
+        }
+
+        assert_eq!(data, contents());
+        assert_eq!(read_at(&mut *file, 4096, 100), Vec::<u8>::new());
+
+        // The file itself, then the handle of its worker for every window
+        assert_eq!(opens.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn closed_files_can_be_deleted() {
+        let files: Vec<(String, Vec<u8>)> = (0..16).map(|idx| (format!("file{}", idx), contents())).collect();
+        let files: Vec<(&str, &[u8])> = files.iter().map(|(name, data)| (name.as_str(), data.as_slice())).collect();
+        let fs = ReadAheadFileSystem::with_window(memory_with(&files), WINDOW);
+
+        // The worker handles have to be closed along with the files, or the deletions would randomly find them in use
+        for (name, data) in &files {
+            let mut file = fs.open_file(&path(name), READ).unwrap();
+            let mut read = Vec::new();
+
+            while read.len() < data.len() {
+                read.extend_from_slice(&read_at(&mut *file, read.len(), 100));
+            }
+
+            drop(file);
+            assert_eq!(fs.delete_file(&path(name)), AccessorResult::Success);
+        }
+    }
+
+    #[test]
+    fn random_reads_dont_read_ahead() {
+        let (fs, opens) = counting(&[("file", &contents())]);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+
+        for &offset in &[0, 1000, 50, 3000, 2000] {
+            assert_eq!(read_at(&mut *file, offset, 64), &contents()[offset..offset + 64]);
+        }
+
+        assert_eq!(opens.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn windows_are_served_from_memory() {
+        let fs = ReadAheadFileSystem::with_window(memory_with(&[("file", &contents())]), WINDOW);
+        let mut file = fs.open_file(&path("file"), READ).unwrap();
+
+        // The second sequential read starts fetching the window at 128, the third one waits for it
+        read_at(&mut *file, 0, 64);
+        read_at(&mut *file, 64, 64);
+        assert_eq!(read_at(&mut *file, 128, 64), &contents()[128..192]);
+
+        overwrite(fs.inner(), "file", 192, &[0; 64]);
+        assert_eq!(read_at(&mut *file, 192, 64), &contents()[192..256]);
+
+        // Seeking away drops the window
+        read_at(&mut *file, 0, 1);
+        assert_eq!(read_at(&mut *file, 192, 64), vec![0; 64]);
+    }
+
+    #[test]
+    fn writes_drop_the_window() {
+        let fs = ReadAheadFileSystem::with_window(memory_with(&[("file", &contents())]), WINDOW);
+        let mut file = fs.open_file(&path("file"), READ | WRITE).unwrap();
+
+        read_at(&mut *file, 0, 64);
+        read_at(&mut *file, 64, 64);
+        read_at(&mut *file, 128, 64);
+
+        file.write(&[1; 64], 192, false).unwrap();
+        assert_eq!(read_at(&mut *file, 192, 64), vec![1; 64]);
+
+        file.set_size(200).unwrap();
+        assert_eq!(read_at(&mut *file, 192, 64), vec![1; 8]);
+    }
+
+    #[test]
+    fn prefetched_files_are_served_until_modified() {
+        let fs = ReadAheadFileSystem::new(memory_with(&[("a", b"aaaa"), ("b", b"bbbb")]));
+        let mut writer = fs.open_file(&path("a"), READ | WRITE).unwrap();
+
+        fs.prefetch(vec![path("a"), path("b"), path("missing")]).join().unwrap();
+
+        overwrite(fs.inner(), "a", 0, b"AA");
+        overwrite(fs.inner(), "b", 0, b"BB");
+        assert_eq!(read_path(&fs, "a"), Ok(b"aaaa".to_vec()));
+        assert_eq!(read_path(&fs, "b"), Ok(b"bbbb".to_vec()));
+
+        // A file opened before the prefetch still evicts it when written to
+        writer.write(b"x", 3, false).unwrap();
+        assert_eq!(read_path(&fs, "a"), Ok(b"AAax".to_vec()));
+
+        let mut prefetched = fs.open_file(&path("b"), READ).unwrap();
+        assert_eq!(prefetched.operate_range(OperateRangeId::Invalidate, 0, 4, &mut QueryRangeInfo::default()), AccessorResult::Success);
+        assert_eq!(read_path(&fs, "b"), Ok(b"BBbb".to_vec()));
+
+        fs.prefetch(vec![path("a")]).join().unwrap();
+        fs.clear_prefetched();
+        overwrite(fs.inner(), "a", 0, b"C");
+        assert_eq!(read_path(&fs, "a"), Ok(b"CAax".to_vec()));
+    }
+}
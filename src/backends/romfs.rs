@@ -0,0 +1,493 @@
+use std::convert::TryInto;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use super::host_directory::to_accessor_result;
+use super::{ read_exact, ArchiveFile, HostFile };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntry, DirectoryEntryType, DirectoryListing, FileAccessor, FileSystemAccessor, FsEntryType, NnPath};
+
+const HEADER_SIZE: usize = 0x50;
+const DIRECTORY_ENTRY_SIZE: usize = 0x18;
+const FILE_ENTRY_SIZE: usize = 0x20;
+
+/// Marks the end of sibling and hash bucket chains
+const NO_ENTRY: u32 = 0xffff_ffff;
+
+const ROOT_DIRECTORY: u32 = 0;
+
+fn u32_at(data: &[u8], offset: usize) -> Result<u32, AccessorResult> {
+    Ok(u32::from_le_bytes(data.get(offset..offset + 4).ok_or(AccessorResult::Unexpected)?.try_into().unwrap()))
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Result<u64, AccessorResult> {
+    Ok(u64::from_le_bytes(data.get(offset..offset + 8).ok_or(AccessorResult::Unexpected)?.try_into().unwrap()))
+}
+
+/// Hash used to place entries in the hash tables, from the offset of their parent directory and their name.
+fn entry_hash(parent: u32, name: &[u8]) -> u32 {
+    name.iter().fold(parent ^ 123456789, |hash, &byte| hash.rotate_right(5) ^ byte as u32)
+}
+
+struct DirectoryMeta {
+    sibling: u32,
+    first_directory: u32,
+    first_file: u32,
+}
+
+struct FileMeta {
+    sibling: u32,
+    offset: u64,
+    size: u64,
+}
+
+/// One of the two hash table and metadata table pairs of the image.
+struct Table {
+    buckets: Vec<u32>,
+    entries: Vec<u8>,
+    entry_size: usize,
+}
+
+impl Table {
+    /// Read the table whose hash table and metadata table offsets and sizes start at `offset` in the header.
+    fn read<F: FileAccessor>(image: &mut F, header: &[u8], offset: usize, entry_size: usize) -> Result<Self, AccessorResult> {
+        let image_size = image.get_size()? as u64;
+
+        // Checked before allocating, so a corrupted header can't ask for more memory than the image holds
+        let region = |at: usize| -> Result<(usize, usize), AccessorResult> {
+            let (start, size) = (u64_at(header, at)?, u64_at(header, at + 8)?);
+
+            match start.checked_add(size) {
+                Some(end) if end <= image_size => Ok((start as usize, size as usize)),
+                _ => Err(AccessorResult::Unexpected),
+            }
+        };
+
+        let (buckets_offset, buckets_size) = region(offset)?;
+        let (entries_offset, entries_size) = region(offset + 0x10)?;
+
+        let mut buckets = vec![0; buckets_size];
+        let mut entries = vec![0; entries_size];
+
+        read_exact(image, &mut buckets, buckets_offset)?;
+        read_exact(image, &mut entries, entries_offset)?;
+
+        Ok(Self {
+            buckets: buckets.chunks_exact(4).map(|bucket| u32::from_le_bytes(bucket.try_into().unwrap())).collect(),
+            entries,
+            entry_size,
+        })
+    }
+
+    /// Most entries a chain can go through, every entry taking at least `entry_size` bytes of the table.
+    /// Walking further means the chain loops.
+    fn max_chain_length(&self) -> usize {
+        self.entries.len() / self.entry_size
+    }
+
+    /// Name of the entry at `offset`, which follows its fixed-size part.
+    fn name(&self, offset: u32) -> Result<&[u8], AccessorResult> {
+        let start = offset as usize + self.entry_size;
+        let length = u32_at(&self.entries, start - 4)? as usize;
+
+        self.entries.get(start..start + length).ok_or(AccessorResult::Unexpected)
+    }
+
+    /// Look up the entry named `name` in the directory at `parent`.
+    fn find(&self, parent: u32, name: &[u8]) -> Result<Option<u32>, AccessorResult> {
+        if self.buckets.is_empty() {
+            return Ok(None);
+        }
+
+        let mut offset = self.buckets[entry_hash(parent, name) as usize % self.buckets.len()];
+        let mut remaining = self.max_chain_length();
+
+        while offset != NO_ENTRY {
+            remaining = remaining.checked_sub(1).ok_or(AccessorResult::Unexpected)?;
+
+            if u32_at(&self.entries, offset as usize)? == parent && self.name(offset)? == name {
+                return Ok(Some(offset));
+            }
+
+            // Next entry in the same bucket, stored right before the name length
+            offset = u32_at(&self.entries, offset as usize + self.entry_size - 8)?;
+        }
+
+        Ok(None)
+    }
+}
+
+enum Entry {
+    Directory(u32),
+    File(u32),
+}
+
+/// A read-only view of a RomFS image, read from any `FileAccessor`.
+///
+/// Only the hash and metadata tables are kept in memory, file contents are read from the image as needed.
+pub struct RomFsFileSystem<F: FileAccessor> {
+    image: Arc<Mutex<F>>,
+    directories: Table,
+    files: Table,
+    data_offset: usize,
+}
+
+impl RomFsFileSystem<HostFile> {
+    /// Open the RomFS image at `path` on the host filesystem.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, AccessorResult> {
+        Self::new(HostFile::open(path).map_err(to_accessor_result)?)
+    }
+}
+
+impl<F: FileAccessor> RomFsFileSystem<F> {
+    pub fn new(mut image: F) -> Result<Self, AccessorResult> {
+        let mut header = [0; HEADER_SIZE];
+        read_exact(&mut image, &mut header, 0)?;
+
+        if u64_at(&header, 0)? != HEADER_SIZE as u64 {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        Ok(Self {
+            directories: Table::read(&mut image, &header, 0x8, DIRECTORY_ENTRY_SIZE)?,
+            files: Table::read(&mut image, &header, 0x28, FILE_ENTRY_SIZE)?,
+            data_offset: u64_at(&header, 0x48)? as usize,
+            image: Arc::new(Mutex::new(image)),
+        })
+    }
+
+    fn directory(&self, offset: u32) -> Result<DirectoryMeta, AccessorResult> {
+        let entry = offset as usize;
+
+        Ok(DirectoryMeta {
+            sibling: u32_at(&self.directories.entries, entry + 4)?,
+            first_directory: u32_at(&self.directories.entries, entry + 8)?,
+            first_file: u32_at(&self.directories.entries, entry + 0xc)?,
+        })
+    }
+
+    fn file(&self, offset: u32) -> Result<FileMeta, AccessorResult> {
+        let entry = offset as usize;
+
+        Ok(FileMeta {
+            sibling: u32_at(&self.files.entries, entry + 4)?,
+            offset: u64_at(&self.files.entries, entry + 8)?,
+            size: u64_at(&self.files.entries, entry + 0x10)?,
+        })
+    }
+
+    fn lookup(&self, path: &NnPath) -> Result<Entry, AccessorResult> {
+        let mut components = path.components().peekable();
+        let mut directory = ROOT_DIRECTORY;
+
+        while let Some(name) = components.next() {
+            let name = name.as_bytes();
+
+            if components.peek().is_none() {
+                if let Some(file) = self.files.find(directory, name)? {
+                    return Ok(Entry::File(file));
+                }
+            }
+
+            directory = self.directories.find(directory, name)?.ok_or(AccessorResult::PathNotFound)?;
+        }
+
+        Ok(Entry::Directory(directory))
+    }
+}
+
+impl<F: FileAccessor + 'static> FileSystemAccessor for RomFsFileSystem<F> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        match self.lookup(path)? {
+            Entry::Directory(_) => Ok(FsEntryType::Directory),
+            Entry::File(_) => Ok(FsEntryType::File),
+        }
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let file = match self.lookup(path)? {
+            Entry::File(offset) => self.file(offset)?,
+            Entry::Directory(_) => return Err(AccessorResult::PathNotFound),
+        };
+
+        Ok(Box::new(ArchiveFile::new(self.image.clone(), self.data_offset + file.offset as usize, file.size as usize)))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let directory = match self.lookup(path)? {
+            Entry::Directory(offset) => self.directory(offset)?,
+            Entry::File(_) => return Err(AccessorResult::PathNotFound),
+        };
+
+        let mut entries = Vec::new();
+        let mut child = directory.first_directory;
+        let mut remaining = self.directories.max_chain_length();
+
+        while child != NO_ENTRY {
+            remaining = remaining.checked_sub(1).ok_or(AccessorResult::Unexpected)?;
+
+            entries.push(DirectoryEntry {
+                path: PathBuf::from(String::from_utf8_lossy(self.directories.name(child)?).into_owned()),
+                ty: DirectoryEntryType::Directory,
+                timestamp: None,
+            });
+
+            child = self.directory(child)?.sibling;
+        }
+
+        let mut child = directory.first_file;
+        let mut remaining = self.files.max_chain_length();
+
+        while child != NO_ENTRY {
+            remaining = remaining.checked_sub(1).ok_or(AccessorResult::Unexpected)?;
+
+            let file = self.file(child)?;
+
+            entries.push(DirectoryEntry {
+                path: PathBuf::from(String::from_utf8_lossy(self.files.name(child)?).into_owned()),
+                ty: DirectoryEntryType::File(file.size as i64),
+                timestamp: None,
+            });
+
+            child = file.sibling;
+        }
+
+        Ok(Box::new(DirectoryListing::new(entries, mode)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_file, path, read_path, READ };
+
+    const BUCKET_COUNT: usize = 3;
+
+    fn aligned(size: usize, alignment: usize) -> usize {
+        (size + alignment - 1) & !(alignment - 1)
+    }
+
+    struct Node {
+        name: String,
+        parent: usize,
+        offset: u32,
+        children: Vec<usize>,
+    }
+
+    fn assign_offsets(nodes: &mut [Node], entry_size: usize) {
+        let mut offset = 0;
+
+        for node in nodes {
+            node.offset = offset as u32;
+            offset += entry_size + aligned(node.name.len(), 4);
+        }
+    }
+
+    /// Hash buckets of a table, along with the parent of each entry and the next entry in its bucket.
+    fn link(nodes: &[Node], parent_offsets: &[u32]) -> (Vec<u32>, Vec<(u32, u32)>) {
+        let mut buckets = vec![NO_ENTRY; BUCKET_COUNT];
+
+        let links = nodes.iter().map(|node| {
+            let parent = parent_offsets[node.parent];
+            let bucket = entry_hash(parent, node.name.as_bytes()) as usize % BUCKET_COUNT;
+
+            (parent, std::mem::replace(&mut buckets[bucket], node.offset))
+        }).collect();
+
+        (buckets, links)
+    }
+
+    fn push_name(table: &mut Vec<u8>, name: &str) {
+        table.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        table.extend_from_slice(name.as_bytes());
+        table.resize(aligned(table.len(), 4), 0);
+    }
+
+    /// A RomFS image holding `files`, directories being created for their ancestors.
+    fn build_romfs(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut directories = vec![Node { name: String::new(), parent: 0, offset: 0, children: Vec::new() }];
+        let mut file_nodes = Vec::new();
+        let mut file_children = vec![Vec::new()];
+
+        for (file, _) in files {
+            let components: Vec<&str> = file.split('/').collect();
+            let mut parent = 0;
+
+            for name in &components[..components.len() - 1] {
+                parent = match directories[parent].children.iter().find(|&&child| directories[child].name == *name) {
+                    Some(&child) => child,
+                    None => {
+                        let child = directories.len();
+
+                        directories.push(Node { name: name.to_string(), parent, offset: 0, children: Vec::new() });
+                        directories[parent].children.push(child);
+                        file_children.push(Vec::new());
+                        child
+                    },
+                };
+            }
+
+            file_children[parent].push(file_nodes.len());
+            file_nodes.push(Node { name: components[components.len() - 1].to_string(), parent, offset: 0, children: Vec::new() });
+        }
+
+        assign_offsets(&mut directories, DIRECTORY_ENTRY_SIZE);
+        assign_offsets(&mut file_nodes, FILE_ENTRY_SIZE);
+
+        // The root is its own parent
+        let directory_offsets: Vec<u32> = directories.iter().map(|directory| directory.offset).collect();
+        let (directory_buckets, directory_links) = link(&directories, &directory_offsets);
+        let (file_buckets, file_links) = link(&file_nodes, &directory_offsets);
+
+        let sibling = |siblings: &[usize], index: usize, offsets: &dyn Fn(usize) -> u32| {
+            match siblings.iter().position(|&sibling| sibling == index) {
+                Some(position) if position + 1 < siblings.len() => offsets(siblings[position + 1]),
+                _ => NO_ENTRY,
+            }
+        };
+
+        let mut directory_table = Vec::new();
+
+        for (index, (directory, &(parent, next))) in directories.iter().zip(&directory_links).enumerate() {
+            let siblings: &[usize] = if index == 0 { &[] } else { &directories[directory.parent].children };
+
+            directory_table.extend_from_slice(&parent.to_le_bytes());
+            directory_table.extend_from_slice(&sibling(siblings, index, &|child| directories[child].offset).to_le_bytes());
+            directory_table.extend_from_slice(&directory.children.first().map_or(NO_ENTRY, |&child| directories[child].offset).to_le_bytes());
+            directory_table.extend_from_slice(&file_children[index].first().map_or(NO_ENTRY, |&child| file_nodes[child].offset).to_le_bytes());
+            directory_table.extend_from_slice(&next.to_le_bytes());
+            push_name(&mut directory_table, &directory.name);
+        }
+
+        let mut data = Vec::new();
+        let mut file_table = Vec::new();
+
+        for (index, (file, &(parent, next))) in file_nodes.iter().zip(&file_links).enumerate() {
+            let contents = files[index].1;
+
+            file_table.extend_from_slice(&parent.to_le_bytes());
+            file_table.extend_from_slice(&sibling(&file_children[file.parent], index, &|child| file_nodes[child].offset).to_le_bytes());
+            file_table.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            file_table.extend_from_slice(&(contents.len() as u64).to_le_bytes());
+            file_table.extend_from_slice(&next.to_le_bytes());
+            push_name(&mut file_table, &file.name);
+
+            data.extend_from_slice(contents);
+            data.resize(aligned(data.len(), 0x10), 0);
+        }
+
+        let bucket_bytes = |buckets: &[u32]| buckets.iter().flat_map(|bucket| bucket.to_le_bytes().to_vec()).collect::<Vec<u8>>();
+        let sections = [bucket_bytes(&directory_buckets), directory_table, bucket_bytes(&file_buckets), file_table];
+
+        let mut header = vec![HEADER_SIZE as u64];
+        let mut offset = HEADER_SIZE + data.len();
+
+        for section in &sections {
+            header.extend_from_slice(&[offset as u64, section.len() as u64]);
+            offset += section.len();
+        }
+
+        header.push(HEADER_SIZE as u64);
+
+        let mut image: Vec<u8> = header.iter().flat_map(|value| value.to_le_bytes().to_vec()).collect();
+        image.extend_from_slice(&data);
+        sections.iter().for_each(|section| image.extend_from_slice(section));
+        image
+    }
+
+    fn sample() -> Vec<u8> {
+        build_romfs(&[
+            ("root.txt", b"root file"),
+            ("a/x.txt", b"hello x"),
+            ("a/sub/deep.bin", &[7; 200]),
+            ("a/y.txt", b""),
+            ("b/z.txt", b"z"),
+        ])
+    }
+
+    fn patch_u32(image: &mut [u8], offset: usize, value: u32) {
+        image[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+
+    fn patch_u64(image: &mut [u8], offset: usize, value: u64) {
+        image[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+
+    /// Offset of the file table in `image`.
+    fn file_table(image: &[u8]) -> usize {
+        u64_at(image, 0x38).unwrap() as usize
+    }
+
+    #[test]
+    fn finds_files_and_directories() {
+        let romfs = RomFsFileSystem::new(memory_file(&sample())).unwrap();
+
+        assert_eq!(read_path(&romfs, "root.txt"), Ok(b"root file".to_vec()));
+        assert_eq!(read_path(&romfs, "a/x.txt"), Ok(b"hello x".to_vec()));
+        assert_eq!(read_path(&romfs, "a/sub/deep.bin"), Ok(vec![7; 200]));
+        assert_eq!(read_path(&romfs, "a/y.txt"), Ok(Vec::new()));
+        assert_eq!(read_path(&romfs, "b/z.txt"), Ok(b"z".to_vec()));
+
+        assert_eq!(romfs.get_entry_type(&path("a/sub")), Ok(FsEntryType::Directory));
+        assert_eq!(romfs.get_entry_type(&path("a/nope")), Err(AccessorResult::PathNotFound));
+        assert_eq!(romfs.get_entry_type(&path("root.txt/x")), Err(AccessorResult::PathNotFound));
+        assert_eq!(romfs.open_file(&path("a"), READ).err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn lists_directories_then_files() {
+        let romfs = RomFsFileSystem::new(memory_file(&sample())).unwrap();
+
+        assert_eq!(list_path(&romfs, ""), Ok(vec![("a".to_string(), None), ("b".to_string(), None), ("root.txt".to_string(), Some(9))]));
+        assert_eq!(list_path(&romfs, "a"), Ok(vec![("sub".to_string(), None), ("x.txt".to_string(), Some(7)), ("y.txt".to_string(), Some(0))]));
+        assert_eq!(list_path(&romfs, "a/sub"), Ok(vec![("deep.bin".to_string(), Some(200))]));
+        assert_eq!(list_path(&romfs, "root.txt").err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn looping_sibling_chains_are_rejected() {
+        let mut image = build_romfs(&[("a.txt", b"a")]);
+        let entry = file_table(&image);
+
+        // The only file is its own sibling
+        patch_u32(&mut image, entry + 4, 0);
+
+        let romfs = RomFsFileSystem::new(memory_file(&image)).unwrap();
+
+        assert_eq!(list_path(&romfs, "").err(), Some(AccessorResult::Unexpected));
+    }
+
+    #[test]
+    fn looping_hash_chains_are_rejected() {
+        let mut image = build_romfs(&[("a.txt", b"a")]);
+        let entry = file_table(&image);
+
+        // Moved out of the root so it never matches, and linked to itself in its bucket
+        patch_u32(&mut image, entry, 0x18);
+        patch_u32(&mut image, entry + FILE_ENTRY_SIZE - 8, 0);
+
+        let romfs = RomFsFileSystem::new(memory_file(&image)).unwrap();
+
+        assert_eq!(romfs.get_entry_type(&path("a.txt")), Err(AccessorResult::Unexpected));
+    }
+
+    #[test]
+    fn tables_must_fit_in_the_image() {
+        let image = sample();
+
+        let mut oversized = image.clone();
+        patch_u64(&mut oversized, 0x40, image.len() as u64);
+        assert!(RomFsFileSystem::new(memory_file(&oversized)).is_err());
+
+        let mut huge = image.clone();
+        patch_u64(&mut huge, 0x20, u64::MAX / 2);
+        assert!(RomFsFileSystem::new(memory_file(&huge)).is_err());
+
+        let mut overflowing = image.clone();
+        patch_u64(&mut overflowing, 0x38, u64::MAX);
+        assert!(RomFsFileSystem::new(memory_file(&overflowing)).is_err());
+
+        assert!(RomFsFileSystem::new(memory_file(&image[..HEADER_SIZE - 1])).is_err());
+    }
+}
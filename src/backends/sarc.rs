@@ -0,0 +1,449 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use skyline::nn;
+
+use super::compression::decompress_if_needed;
+use super::{ is_writable, read_to_end, to_result, MemoryFileSystem };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryEntryType, FileAccessor, FileSystemAccessor, FsEntryType, NnPath};
+
+const SARC_MAGIC: &[u8] = b"SARC";
+const SFAT_MAGIC: &[u8] = b"SFAT";
+const SFNT_MAGIC: &[u8] = b"SFNT";
+
+const SARC_HEADER_SIZE: usize = 0x14;
+const SFAT_HEADER_SIZE: usize = 0xc;
+const SFAT_NODE_SIZE: usize = 0x10;
+const SFNT_HEADER_SIZE: usize = 0x8;
+
+const SARC_VERSION: u16 = 0x100;
+const DEFAULT_HASH_KEY: u32 = 0x65;
+const NODE_HAS_NAME: u32 = 0x0100_0000;
+
+const MIN_ALIGNMENT: usize = 4;
+const MAX_ALIGNMENT: usize = 0x2000;
+
+/// Byte order of a SARC archive, given by its byte order mark.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Endianness {
+    Big,
+    Little,
+}
+
+impl Endianness {
+    fn u16_at(self, data: &[u8], offset: usize) -> Result<u16, AccessorResult> {
+        let bytes = data.get(offset..offset + 2).ok_or(AccessorResult::Unexpected)?.try_into().unwrap();
+
+        Ok(match self {
+            Endianness::Big => u16::from_be_bytes(bytes),
+            Endianness::Little => u16::from_le_bytes(bytes),
+        })
+    }
+
+    fn u32_at(self, data: &[u8], offset: usize) -> Result<u32, AccessorResult> {
+        let bytes = data.get(offset..offset + 4).ok_or(AccessorResult::Unexpected)?.try_into().unwrap();
+
+        Ok(match self {
+            Endianness::Big => u32::from_be_bytes(bytes),
+            Endianness::Little => u32::from_le_bytes(bytes),
+        })
+    }
+
+    fn put_u16(self, out: &mut Vec<u8>, value: u16) {
+        match self {
+            Endianness::Big => out.extend_from_slice(&value.to_be_bytes()),
+            Endianness::Little => out.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+
+    fn put_u32(self, out: &mut Vec<u8>, value: u32) {
+        match self {
+            Endianness::Big => out.extend_from_slice(&value.to_be_bytes()),
+            Endianness::Little => out.extend_from_slice(&value.to_le_bytes()),
+        }
+    }
+}
+
+fn name_hash(name: &str, key: u32) -> u32 {
+    // The game hashes names as signed chars
+    name.bytes().fold(0u32, |hash, byte| hash.wrapping_mul(key).wrapping_add(byte as i8 as u32))
+}
+
+fn align(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+/// Name given to files stored without one, which can only be looked up by hash.
+fn unnamed_file_name(hash: u32) -> String {
+    format!("{:08x}.bin", hash)
+}
+
+/// Exposes the files of a SARC archive, which can be wrapped in Yaz0 or, with the `zstd` and `lz4` features, zstd and LZ4 compression.
+///
+/// The archive is extracted in memory and read-only unless `writable` is called, in which case `rebuild` serializes the current contents back to a SARC.
+/// Files stored without a name show up as `<hash>.bin` at the root of the archive.
+pub struct SarcFileSystem {
+    files: MemoryFileSystem,
+    endianness: Endianness,
+    hash_key: u32,
+    alignment: usize,
+    unnamed: BTreeMap<NnPath, u32>,
+    writable: bool,
+}
+
+impl SarcFileSystem {
+    pub fn new<F: FileAccessor>(mut source: F) -> Result<Self, AccessorResult> {
+        Self::from_bytes(read_to_end(&mut source)?)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> Result<Self, AccessorResult> {
+        let data = decompress_if_needed(data)?;
+
+        if data.len() < SARC_HEADER_SIZE || !data.starts_with(SARC_MAGIC) {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let endianness = match &data[6..8] {
+            [0xfe, 0xff] => Endianness::Big,
+            [0xff, 0xfe] => Endianness::Little,
+            _ => return Err(AccessorResult::Unexpected),
+        };
+
+        let sfat = endianness.u16_at(&data, 4)? as usize;
+        let data_offset = endianness.u32_at(&data, 0xc)? as usize;
+
+        if !data.get(sfat..).unwrap_or(&[]).starts_with(SFAT_MAGIC) {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let node_count = endianness.u16_at(&data, sfat + 6)? as usize;
+        let hash_key = endianness.u32_at(&data, sfat + 8)?;
+        let nodes = sfat + endianness.u16_at(&data, sfat + 4)? as usize;
+        let sfnt = nodes + node_count * SFAT_NODE_SIZE;
+
+        if !data.get(sfnt..).unwrap_or(&[]).starts_with(SFNT_MAGIC) {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let names = sfnt + endianness.u16_at(&data, sfnt + 4)? as usize;
+
+        let mut sarc = Self {
+            files: MemoryFileSystem::new(),
+            endianness,
+            hash_key,
+            alignment: MAX_ALIGNMENT,
+            unnamed: BTreeMap::new(),
+            writable: false,
+        };
+
+        for node in (0..node_count).map(|idx| nodes + idx * SFAT_NODE_SIZE) {
+            let hash = endianness.u32_at(&data, node)?;
+            let attributes = endianness.u32_at(&data, node + 4)?;
+            let start = data_offset + endianness.u32_at(&data, node + 8)? as usize;
+            let end = data_offset + endianness.u32_at(&data, node + 12)? as usize;
+
+            let path = if attributes & 0xff00_0000 != 0 {
+                let name = data.get(names + (attributes & 0xffff) as usize * 4..).ok_or(AccessorResult::Unexpected)?;
+                let name = &name[..name.iter().position(|&byte| byte == 0).ok_or(AccessorResult::Unexpected)?];
+
+                NnPath::new(String::from_utf8_lossy(name))?
+            } else {
+                let path = NnPath::new(unnamed_file_name(hash))?;
+                sarc.unnamed.insert(path.clone(), hash);
+                path
+            };
+
+            let contents = data.get(start..end).ok_or(AccessorResult::Unexpected)?;
+
+            // Keep the strictest alignment the archive satisfies, some formats inside need their data aligned
+            if !contents.is_empty() {
+                sarc.alignment = sarc.alignment.min(1 << start.trailing_zeros().min(31));
+            }
+
+            sarc.insert_file(&path, contents)?;
+        }
+
+        sarc.alignment = sarc.alignment.max(MIN_ALIGNMENT);
+
+        Ok(sarc)
+    }
+
+    /// A new archive with no files, ready to be filled and rebuilt.
+    pub fn empty(endianness: Endianness) -> Self {
+        Self {
+            files: MemoryFileSystem::new(),
+            endianness,
+            hash_key: DEFAULT_HASH_KEY,
+            alignment: MIN_ALIGNMENT,
+            unnamed: BTreeMap::new(),
+            writable: true,
+        }
+    }
+
+    /// Allow changes to the archive's contents, which `rebuild` can then save.
+    pub fn writable(mut self) -> Self {
+        self.writable = true;
+        self
+    }
+
+    pub fn endianness(&self) -> Endianness {
+        self.endianness
+    }
+
+    fn insert_file(&self, path: &NnPath, contents: &[u8]) -> Result<(), AccessorResult> {
+        let mut missing: Vec<NnPath> = path.ancestors().skip(1).take_while(|ancestor| self.files.get_entry_type(ancestor).is_err()).collect();
+
+        while let Some(directory) = missing.pop() {
+            to_result(self.files.create_directory(&directory))?;
+        }
+
+        to_result(self.files.create_file(path, 0))?;
+
+        self.files.open_file(path, nn::fs::OpenMode_OpenMode_Write | nn::fs::OpenMode_OpenMode_Append)?.write(contents, 0, true)
+    }
+
+    fn collect_files(&self, directory: &NnPath, files: &mut Vec<(NnPath, Vec<u8>)>) -> Result<(), AccessorResult> {
+        let mut listing = self.files.open_directory(directory, nn::fs::OpenDirectoryMode_OpenDirectoryMode_All)?;
+
+        for entry in crate::accessors::read_all_entries(&mut *listing)? {
+            let path = directory.join(entry.path.to_str().ok_or(AccessorResult::Unexpected)?)?;
+
+            match entry.ty {
+                DirectoryEntryType::Directory => self.collect_files(&path, files)?,
+                DirectoryEntryType::File(_) => {
+                    let data = read_to_end(&mut *self.files.open_file(&path, nn::fs::OpenMode_OpenMode_Read)?)?;
+                    files.push((path, data));
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Serialize the current contents to an uncompressed SARC, keeping the original byte order, hash key and data alignment.
+    ///
+    /// Empty directories can't be represented and are dropped.
+    pub fn rebuild(&self) -> Result<Vec<u8>, AccessorResult> {
+        let mut files = Vec::new();
+        self.collect_files(&NnPath::root(), &mut files)?;
+
+        let mut nodes: Vec<(u32, Option<&str>, &[u8])> = files.iter().map(|(path, data)| match self.unnamed.get(path) {
+            Some(hash) => (*hash, None, data.as_slice()),
+            None => (name_hash(path.as_str(), self.hash_key), Some(path.as_str()), data.as_slice()),
+        }).collect();
+
+        // The game binary searches the nodes by hash
+        nodes.sort_by(|a, b| (a.0, a.1).cmp(&(b.0, b.1)));
+
+        let endianness = self.endianness;
+        let mut names = Vec::new();
+        let mut contents = Vec::new();
+        let mut sfat = Vec::with_capacity(nodes.len() * SFAT_NODE_SIZE);
+
+        for (hash, name, data) in nodes.iter() {
+            let attributes = match name {
+                Some(name) => {
+                    let attributes = NODE_HAS_NAME | (names.len() / 4) as u32;
+
+                    names.extend_from_slice(name.as_bytes());
+                    names.resize(align(names.len() + 1, 4), 0);
+                    attributes
+                },
+                None => 0,
+            };
+
+            contents.resize(align(contents.len(), self.alignment), 0);
+
+            endianness.put_u32(&mut sfat, *hash);
+            endianness.put_u32(&mut sfat, attributes);
+            endianness.put_u32(&mut sfat, contents.len() as u32);
+            endianness.put_u32(&mut sfat, (contents.len() + data.len()) as u32);
+
+            contents.extend_from_slice(data);
+        }
+
+        let header_size = SARC_HEADER_SIZE + SFAT_HEADER_SIZE + sfat.len() + SFNT_HEADER_SIZE + names.len();
+        let data_offset = align(header_size, self.alignment);
+
+        let mut out = Vec::with_capacity(data_offset + contents.len());
+
+        out.extend_from_slice(SARC_MAGIC);
+        endianness.put_u16(&mut out, SARC_HEADER_SIZE as u16);
+        endianness.put_u16(&mut out, 0xfeff);
+        endianness.put_u32(&mut out, (data_offset + contents.len()) as u32);
+        endianness.put_u32(&mut out, data_offset as u32);
+        endianness.put_u16(&mut out, SARC_VERSION);
+        endianness.put_u16(&mut out, 0);
+
+        out.extend_from_slice(SFAT_MAGIC);
+        endianness.put_u16(&mut out, SFAT_HEADER_SIZE as u16);
+        endianness.put_u16(&mut out, nodes.len() as u16);
+        endianness.put_u32(&mut out, self.hash_key);
+        out.extend_from_slice(&sfat);
+
+        out.extend_from_slice(SFNT_MAGIC);
+        endianness.put_u16(&mut out, SFNT_HEADER_SIZE as u16);
+        endianness.put_u16(&mut out, 0);
+        out.extend_from_slice(&names);
+
+        out.resize(data_offset, 0);
+        out.extend_from_slice(&contents);
+
+        Ok(out)
+    }
+
+    fn expect_writable(&self) -> Result<(), AccessorResult> {
+        if self.writable {
+            Ok(())
+        } else {
+            Err(AccessorResult::Unsupported)
+        }
+    }
+
+    fn write(&self, f: impl FnOnce(&MemoryFileSystem) -> AccessorResult) -> AccessorResult {
+        match self.expect_writable() {
+            Ok(()) => f(&self.files),
+            Err(e) => e,
+        }
+    }
+}
+
+impl Default for SarcFileSystem {
+    fn default() -> Self {
+        Self::empty(Endianness::Little)
+    }
+}
+
+impl FileSystemAccessor for SarcFileSystem {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.files.get_entry_type(path)
+    }
+
+    fn create_file(&self, path: &NnPath, size: usize) -> AccessorResult {
+        self.write(|files| files.create_file(path, size))
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        if is_writable(mode) {
+            self.expect_writable()?;
+        }
+
+        self.files.open_file(path, mode)
+    }
+
+    fn rename_file(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.write(|files| files.rename_file(path, new_path))
+    }
+
+    fn delete_file(&self, path: &NnPath) -> AccessorResult {
+        self.write(|files| files.delete_file(path))
+    }
+
+    fn create_directory(&self, path: &NnPath) -> AccessorResult {
+        self.write(|files| files.create_directory(path))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        self.files.open_directory(path, mode)
+    }
+
+    fn rename_directory(&self, path: &NnPath, new_path: &NnPath) -> AccessorResult {
+        self.write(|files| files.rename_directory(path, new_path))
+    }
+
+    fn delete_directory(&self, path: &NnPath) -> AccessorResult {
+        self.write(|files| files.delete_directory(path))
+    }
+
+    fn delete_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.write(|files| files.delete_directory_recursively(path))
+    }
+
+    fn clean_directory_recursively(&self, path: &NnPath) -> AccessorResult {
+        self.write(|files| files.clean_directory_recursively(path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_file, path, read_path, APPEND, WRITE };
+
+    fn sample(endianness: Endianness) -> Vec<u8> {
+        let sarc = SarcFileSystem::empty(endianness);
+
+        assert_eq!(sarc.create_directory(&path("dir")), AccessorResult::Success);
+        assert_eq!(sarc.create_file(&path("dir/a.txt"), 0), AccessorResult::Success);
+        assert_eq!(sarc.create_file(&path("b.bin"), 3), AccessorResult::Success);
+        sarc.open_file(&path("dir/a.txt"), WRITE | APPEND).unwrap().write(b"hello", 0, true).unwrap();
+
+        sarc.rebuild().unwrap()
+    }
+
+    #[test]
+    fn round_trips_both_byte_orders() {
+        for &endianness in [Endianness::Big, Endianness::Little].iter() {
+            let data = sample(endianness);
+            let sarc = SarcFileSystem::new(memory_file(&data)).unwrap();
+
+            assert_eq!(sarc.endianness(), endianness);
+            assert_eq!(read_path(&sarc, "dir/a.txt").unwrap(), b"hello");
+            assert_eq!(read_path(&sarc, "b.bin").unwrap(), [0; 3]);
+            assert_eq!(list_path(&sarc, "").unwrap(), vec![("b.bin".to_owned(), Some(3)), ("dir".to_owned(), None)]);
+
+            // Nothing changed, so the rebuilt archive is identical
+            assert_eq!(SarcFileSystem::from_bytes(data.clone()).unwrap().writable().rebuild().unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn nodes_are_sorted_by_hash() {
+        let data = sample(Endianness::Little);
+        let nodes = SARC_HEADER_SIZE + SFAT_HEADER_SIZE;
+        let hashes: Vec<u32> = (0..2).map(|idx| Endianness::Little.u32_at(&data, nodes + idx * SFAT_NODE_SIZE).unwrap()).collect();
+
+        assert!(hashes[0] < hashes[1]);
+        assert!(hashes.contains(&name_hash("dir/a.txt", DEFAULT_HASH_KEY)));
+    }
+
+    #[test]
+    fn unnamed_files_keep_their_hash() {
+        let mut data = sample(Endianness::Little);
+        let node = SARC_HEADER_SIZE + SFAT_HEADER_SIZE;
+        let hash = Endianness::Little.u32_at(&data, node).unwrap();
+
+        // Drop the name of the first node
+        data[node + 4..node + 8].copy_from_slice(&[0; 4]);
+
+        let sarc = SarcFileSystem::from_bytes(data).unwrap().writable();
+        let name = unnamed_file_name(hash);
+
+        assert_eq!(sarc.get_entry_type(&path(&name)), Ok(FsEntryType::File));
+
+        let rebuilt = sarc.rebuild().unwrap();
+        assert_eq!(Endianness::Little.u32_at(&rebuilt, node).unwrap(), hash);
+        assert_eq!(Endianness::Little.u32_at(&rebuilt, node + 4).unwrap(), 0);
+    }
+
+    #[test]
+    fn read_only_unless_writable() {
+        let sarc = SarcFileSystem::from_bytes(sample(Endianness::Big)).unwrap();
+
+        assert_eq!(sarc.create_file(&path("new"), 0), AccessorResult::Unsupported);
+        assert_eq!(sarc.delete_file(&path("b.bin")), AccessorResult::Unsupported);
+        assert_eq!(sarc.open_file(&path("b.bin"), WRITE).err(), Some(AccessorResult::Unsupported));
+
+        let sarc = sarc.writable();
+        assert_eq!(sarc.delete_file(&path("b.bin")), AccessorResult::Success);
+        assert_eq!(read_path(&SarcFileSystem::from_bytes(sarc.rebuild().unwrap()).unwrap(), "b.bin").err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn rejects_malformed_archives() {
+        let data = sample(Endianness::Little);
+
+        assert!(SarcFileSystem::from_bytes(data[..SARC_HEADER_SIZE + 4].to_vec()).is_err());
+        assert!(SarcFileSystem::from_bytes(b"SARC".to_vec()).is_err());
+        assert!(SarcFileSystem::from_bytes(Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,279 @@
+use std::convert::TryInto;
+use std::io::Read;
+
+use super::read_exact;
+use crate::{AccessorResult, FileAccessor, OperateRangeId, QueryRangeInfo};
+
+/// Magic of the skippable frame holding the seek table
+const SEEK_TABLE_MAGIC: u32 = 0x184d_2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92_eab1;
+
+const SKIPPABLE_HEADER_SIZE: usize = 8;
+/// Frame count, descriptor and seekable magic, at the very end of the file
+const FOOTER_SIZE: usize = 9;
+
+/// Set in the descriptor when each seek table entry is followed by a checksum of the frame
+const CHECKSUM_FLAG: u8 = 1 << 7;
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+#[derive(Copy, Clone)]
+pub(crate) struct Frame {
+    compressed_offset: usize,
+    compressed_size: usize,
+    decompressed_offset: usize,
+    decompressed_size: usize,
+}
+
+/// Read the seek table at the end of `file`, or `None` if it doesn't have one.
+pub(crate) fn read_seek_table(file: &mut dyn FileAccessor) -> Result<Option<Vec<Frame>>, AccessorResult> {
+    let file_size = file.get_size()?;
+
+    if file_size < SKIPPABLE_HEADER_SIZE + FOOTER_SIZE {
+        return Ok(None);
+    }
+
+    let mut footer = [0; FOOTER_SIZE];
+    read_exact(file, &mut footer, file_size - FOOTER_SIZE)?;
+
+    if u32_at(&footer, 5) != SEEKABLE_MAGIC {
+        return Ok(None);
+    }
+
+    let frame_count = u32_at(&footer, 0) as usize;
+    let entry_size = if footer[4] & CHECKSUM_FLAG != 0 { 12 } else { 8 };
+    let table_size = frame_count * entry_size + FOOTER_SIZE;
+
+    let table_offset = file_size.checked_sub(SKIPPABLE_HEADER_SIZE + table_size).ok_or(AccessorResult::Unexpected)?;
+    let mut table = vec![0; SKIPPABLE_HEADER_SIZE + table_size - FOOTER_SIZE];
+    read_exact(file, &mut table, table_offset)?;
+
+    if u32_at(&table, 0) != SEEK_TABLE_MAGIC || u32_at(&table, 4) as usize != table_size {
+        return Err(AccessorResult::Unexpected);
+    }
+
+    let mut frames = Vec::with_capacity(frame_count);
+    let (mut compressed_offset, mut decompressed_offset) = (0, 0);
+
+    for entry in table[SKIPPABLE_HEADER_SIZE..].chunks_exact(entry_size) {
+        let frame = Frame {
+            compressed_offset,
+            compressed_size: u32_at(entry, 0) as usize,
+            decompressed_offset,
+            decompressed_size: u32_at(entry, 4) as usize,
+        };
+
+        compressed_offset += frame.compressed_size;
+        decompressed_offset += frame.decompressed_size;
+        frames.push(frame);
+    }
+
+    if compressed_offset > table_offset {
+        return Err(AccessorResult::Unexpected);
+    }
+
+    Ok(Some(frames))
+}
+
+/// Random access to a file in the zstd seekable format, which is split into independent frames listed in a seek table.
+///
+/// Reads only decompress the frames they touch, the last one decompressed is kept around for the next read.
+pub struct SeekableZstdFile<F: FileAccessor> {
+    file: F,
+    frames: Vec<Frame>,
+    size: usize,
+    /// Index and contents of the last frame decompressed
+    cached: Option<(usize, Vec<u8>)>,
+}
+
+impl<F: FileAccessor> SeekableZstdFile<F> {
+    /// Wrap `file`, failing if it doesn't end with a seek table.
+    pub fn new(mut file: F) -> Result<Self, AccessorResult> {
+        match read_seek_table(&mut file)? {
+            Some(frames) => Ok(Self::with_frames(file, frames)),
+            None => Err(AccessorResult::Unexpected),
+        }
+    }
+
+    pub(crate) fn with_frames(file: F, frames: Vec<Frame>) -> Self {
+        Self {
+            size: frames.last().map(|frame| frame.decompressed_offset + frame.decompressed_size).unwrap_or_default(),
+            file,
+            frames,
+            cached: None,
+        }
+    }
+
+    pub fn into_inner(self) -> F {
+        self.file
+    }
+
+    fn frame(&mut self, index: usize) -> Result<&[u8], AccessorResult> {
+        if !matches!(self.cached, Some((cached, _)) if cached == index) {
+            let frame = self.frames[index];
+            let mut compressed = vec![0; frame.compressed_size];
+            read_exact(&mut self.file, &mut compressed, frame.compressed_offset)?;
+
+            let mut data = Vec::with_capacity(frame.decompressed_size);
+            let mut decoder = ruzstd::decoding::StreamingDecoder::new(&compressed[..]).map_err(|_| AccessorResult::Unexpected)?;
+            decoder.read_to_end(&mut data).map_err(|_| AccessorResult::Unexpected)?;
+
+            if data.len() != frame.decompressed_size {
+                return Err(AccessorResult::Unexpected);
+            }
+
+            self.cached = Some((index, data));
+        }
+
+        Ok(&self.cached.as_ref().unwrap().1)
+    }
+}
+
+impl<F: FileAccessor> FileAccessor for SeekableZstdFile<F> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        let mut index = self.frames.partition_point(|frame| frame.decompressed_offset + frame.decompressed_size <= offset);
+        let mut size = 0;
+
+        while size < buffer.len() && index < self.frames.len() {
+            let frame_offset = offset + size - self.frames[index].decompressed_offset;
+            let data = self.frame(index)?;
+            let length = (buffer.len() - size).min(data.len() - frame_offset);
+
+            buffer[size..size + length].copy_from_slice(&data[frame_offset..frame_offset + length]);
+            size += length;
+            index += 1;
+        }
+
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.size)
+    }
+
+    fn flush(&mut self) -> AccessorResult {
+        AccessorResult::Success
+    }
+
+    fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+        match operation {
+            OperateRangeId::Invalidate => {
+                self.cached = None;
+                AccessorResult::Success
+            },
+            OperateRangeId::QueryRange => AccessorResult::Success,
+            _ => AccessorResult::Unsupported,
+        }
+    }
+}
+
+/// Compress `data` in the zstd seekable format, as independent frames of `frame_size` bytes followed by their seek table.
+///
+/// Meant for build tools running on the host, the output can be read by any zstd decoder or randomly accessed through `SeekableZstdFile`.
+pub fn compress_seekable_zstd(data: &[u8], frame_size: usize) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut table = Vec::new();
+
+    for chunk in data.chunks(frame_size.max(1)) {
+        let frame = ruzstd::encoding::compress_to_vec(chunk, ruzstd::encoding::CompressionLevel::Fastest);
+
+        table.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        table.extend_from_slice(&(chunk.len() as u32).to_le_bytes());
+        out.extend_from_slice(&frame);
+    }
+
+    let frame_count = (table.len() / 8) as u32;
+
+    out.extend_from_slice(&SEEK_TABLE_MAGIC.to_le_bytes());
+    out.extend_from_slice(&((table.len() + FOOTER_SIZE) as u32).to_le_bytes());
+    out.extend_from_slice(&table);
+    out.extend_from_slice(&frame_count.to_le_bytes());
+    out.push(0);
+    out.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ memory_file, read_at };
+
+    fn contents() -> Vec<u8> {
+        (0..1000u32).map(|idx| (idx * 7 % 256) as u8).collect()
+    }
+
+    #[test]
+    fn reads_across_frames() {
+        let data = contents();
+        let mut file = SeekableZstdFile::new(memory_file(&compress_seekable_zstd(&data, 100))).unwrap();
+
+        assert_eq!(file.frames.len(), 10);
+        assert_eq!(file.get_size(), Ok(1000));
+
+        // Within a frame, across one boundary, across several, then everything
+        assert_eq!(read_at(&mut file, 10, 50), &data[10..60]);
+        assert_eq!(read_at(&mut file, 90, 20), &data[90..110]);
+        assert_eq!(read_at(&mut file, 150, 520), &data[150..670]);
+        assert_eq!(read_at(&mut file, 0, 1000), data);
+
+        // Starting right on a boundary
+        assert_eq!(read_at(&mut file, 300, 100), &data[300..400]);
+    }
+
+    #[test]
+    fn reads_stop_at_the_end() {
+        let data = contents();
+        let mut file = SeekableZstdFile::new(memory_file(&compress_seekable_zstd(&data, 128))).unwrap();
+
+        assert_eq!(read_at(&mut file, 950, 100), &data[950..]);
+        assert_eq!(read_at(&mut file, 1000, 10), Vec::<u8>::new());
+        assert_eq!(read_at(&mut file, 5000, 10), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn the_last_frame_is_kept() {
+        let data = contents();
+        let mut file = SeekableZstdFile::new(memory_file(&compress_seekable_zstd(&data, 100))).unwrap();
+
+        assert_eq!(read_at(&mut file, 250, 10), &data[250..260]);
+        assert!(matches!(file.cached, Some((2, _))));
+
+        assert_eq!(read_at(&mut file, 260, 10), &data[260..270]);
+        assert!(matches!(file.cached, Some((2, _))));
+
+        assert_eq!(file.operate_range(OperateRangeId::Invalidate, 0, 0, &mut QueryRangeInfo::default()), AccessorResult::Success);
+        assert!(file.cached.is_none());
+        assert_eq!(read_at(&mut file, 260, 10), &data[260..270]);
+    }
+
+    #[test]
+    fn empty_data_has_no_frames() {
+        let mut file = SeekableZstdFile::new(memory_file(&compress_seekable_zstd(&[], 100))).unwrap();
+
+        assert_eq!(file.get_size(), Ok(0));
+        assert_eq!(read_at(&mut file, 0, 10), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn files_without_a_valid_seek_table_are_rejected() {
+        let seekable = compress_seekable_zstd(&contents(), 100);
+        let stream = ruzstd::encoding::compress_to_vec(&contents()[..], ruzstd::encoding::CompressionLevel::Fastest);
+
+        assert!(SeekableZstdFile::new(memory_file(&stream)).is_err());
+        assert!(SeekableZstdFile::new(memory_file(b"short")).is_err());
+
+        // More frames than the table holds
+        let mut truncated = seekable.clone();
+        let count_offset = truncated.len() - FOOTER_SIZE;
+        truncated[count_offset..count_offset + 4].copy_from_slice(&1000u32.to_le_bytes());
+        assert!(SeekableZstdFile::new(memory_file(&truncated)).is_err());
+
+        // Frames larger than the data before the table
+        let mut oversized = seekable;
+        let table_offset = oversized.len() - FOOTER_SIZE - 10 * 8;
+        oversized[table_offset..table_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert!(SeekableZstdFile::new(memory_file(&oversized)).is_err());
+    }
+}
@@ -0,0 +1,433 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use super::host_directory::to_accessor_result;
+use super::{ read_exact, ArchiveFile, ArchiveIndex, HostFile };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath};
+
+const BLOCK_SIZE: usize = 512;
+
+const TYPE_REGULAR: u8 = b'0';
+/// Regular file written by pre-POSIX tar implementations
+const TYPE_REGULAR_OLD: u8 = b'\0';
+const TYPE_DIRECTORY: u8 = b'5';
+/// GNU extension, the data holds the name of the next entry
+const TYPE_LONG_NAME: u8 = b'L';
+/// pax extended header, the data holds records overriding fields of the next entry
+const TYPE_PAX: u8 = b'x';
+/// pax global header, the data holds records applying to every following entry
+const TYPE_PAX_GLOBAL: u8 = b'g';
+
+fn round_up(size: usize) -> usize {
+    (size + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE
+}
+
+/// Header field up to its null terminator.
+fn field(header: &[u8], start: usize, length: usize) -> &[u8] {
+    let field = &header[start..start + length];
+
+    &field[..field.iter().position(|&byte| byte == 0).unwrap_or(field.len())]
+}
+
+/// Parse a numeric header field, in octal or in the base-256 encoding GNU tar uses for values that don't fit.
+fn number(header: &[u8], start: usize, length: usize) -> Result<u64, AccessorResult> {
+    let field = &header[start..start + length];
+
+    if field[0] & 0x80 != 0 {
+        return Ok(field[1..].iter().fold((field[0] & 0x7f) as u64, |value, &byte| value << 8 | byte as u64));
+    }
+
+    let digits = std::str::from_utf8(field).map_err(|_| AccessorResult::Unexpected)?;
+    let digits = digits.trim_matches(|c| c == ' ' || c == '\0');
+
+    if digits.is_empty() {
+        Ok(0)
+    } else {
+        u64::from_str_radix(digits, 8).map_err(|_| AccessorResult::Unexpected)
+    }
+}
+
+/// The checksum is the sum of all the bytes of the header, with the checksum field itself counted as spaces.
+fn is_checksum_valid(header: &[u8]) -> Result<bool, AccessorResult> {
+    let sum: u64 = header.iter().enumerate().map(|(idx, &byte)| if (148..156).contains(&idx) { b' ' as u64 } else { byte as u64 }).sum();
+
+    Ok(number(header, 148, 8)? == sum)
+}
+
+/// Fields of the next entry overridden by a GNU long name or pax extended header.
+#[derive(Default)]
+struct Overrides {
+    path: Option<String>,
+    size: Option<u64>,
+    modified: Option<i64>,
+}
+
+impl Overrides {
+    /// Apply the `"<length> <key>=<value>\n"` records of a pax extended header.
+    fn parse_pax(&mut self, mut records: &[u8]) -> Result<(), AccessorResult> {
+        while !records.is_empty() {
+            let space = records.iter().position(|&byte| byte == b' ').ok_or(AccessorResult::Unexpected)?;
+            let length: usize = std::str::from_utf8(&records[..space]).ok().and_then(|length| length.parse().ok()).ok_or(AccessorResult::Unexpected)?;
+
+            if length <= space + 1 || length > records.len() || records[length - 1] != b'\n' {
+                return Err(AccessorResult::Unexpected);
+            }
+
+            let record = &records[space + 1..length - 1];
+            let equals = record.iter().position(|&byte| byte == b'=').ok_or(AccessorResult::Unexpected)?;
+            let value = String::from_utf8_lossy(&record[equals + 1..]);
+
+            match &record[..equals] {
+                b"path" => self.path = Some(value.into_owned()),
+                b"size" => self.size = Some(value.parse().map_err(|_| AccessorResult::Unexpected)?),
+                // Fractional seconds are dropped
+                b"mtime" => self.modified = value.split('.').next().and_then(|seconds| seconds.parse().ok()),
+                _ => (),
+            }
+
+            records = &records[length..];
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone)]
+struct TarEntry {
+    offset: usize,
+    size: usize,
+    modified: i64,
+}
+
+impl TarEntry {
+    fn timestamp(&self) -> FileTimeStamp {
+        FileTimeStamp {
+            created: self.modified,
+            modified: self.modified,
+            accessed: self.modified,
+            is_local_time: false,
+        }
+    }
+}
+
+/// A read-only view of a tar archive, read from any `FileAccessor`.
+///
+/// The archive is indexed once when opened, then files are read straight from it. Both ustar and pax archives are supported, including GNU long names.
+/// Links and special files are skipped, compressed archives have to be decompressed first.
+pub struct TarFileSystem<F: FileAccessor> {
+    archive: Arc<Mutex<F>>,
+    entries: ArchiveIndex<TarEntry>,
+}
+
+impl TarFileSystem<HostFile> {
+    /// Open the tar archive at `path` on the host filesystem.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, AccessorResult> {
+        Self::new(HostFile::open(path).map_err(to_accessor_result)?)
+    }
+}
+
+impl<F: FileAccessor> TarFileSystem<F> {
+    /// Walk the headers of the archive held by `archive`.
+    pub fn new(mut archive: F) -> Result<Self, AccessorResult> {
+        let archive_size = archive.get_size()?;
+        let mut entries = ArchiveIndex::new();
+        let mut overrides = Overrides::default();
+        let mut offset = 0;
+
+        // The archive ends with two zeroed blocks, but some writers stop right after the last entry
+        while offset + BLOCK_SIZE <= archive_size {
+            let mut header = [0; BLOCK_SIZE];
+            read_exact(&mut archive, &mut header, offset)?;
+
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+
+            if !is_checksum_valid(&header)? {
+                return Err(AccessorResult::Unexpected);
+            }
+
+            let entry_type = header[156];
+            let header_size = number(&header, 124, 12)?;
+
+            // Extension headers are sized by their own header, the overrides only apply to the entry they describe
+            let size = match entry_type {
+                TYPE_LONG_NAME | TYPE_PAX | TYPE_PAX_GLOBAL => header_size,
+                _ => overrides.size.unwrap_or(header_size),
+            } as usize;
+
+            let data_offset = offset + BLOCK_SIZE;
+
+            match data_offset.checked_add(size) {
+                Some(end) if end <= archive_size => (),
+                _ => return Err(AccessorResult::Unexpected),
+            }
+
+            offset = data_offset + round_up(size);
+
+            match entry_type {
+                TYPE_LONG_NAME => {
+                    let mut name = vec![0; size];
+                    read_exact(&mut archive, &mut name, data_offset)?;
+
+                    overrides.path = Some(String::from_utf8_lossy(field(&name, 0, name.len())).into_owned());
+                    continue;
+                },
+                TYPE_PAX => {
+                    let mut records = vec![0; size];
+                    read_exact(&mut archive, &mut records, data_offset)?;
+
+                    overrides.parse_pax(&records)?;
+                    continue;
+                },
+                // Global headers mostly hold comments and defaults nobody relies on, and leave the overrides for the next entry in place
+                TYPE_PAX_GLOBAL => continue,
+                _ => (),
+            }
+
+            let Overrides { path, modified, .. } = std::mem::take(&mut overrides);
+
+            let path = match path {
+                Some(path) => path,
+                // POSIX ustar splits long names between the prefix and name fields, GNU tar uses that space for other fields
+                None if &header[257..263] == b"ustar\0" && header[345] != 0 => {
+                    format!("{}/{}", String::from_utf8_lossy(field(&header, 345, 155)), String::from_utf8_lossy(field(&header, 0, 100)))
+                },
+                None => String::from_utf8_lossy(field(&header, 0, 100)).into_owned(),
+            };
+
+            let path = NnPath::new(&path)?;
+            let modified = match modified {
+                Some(modified) => modified,
+                None => number(&header, 136, 12)? as i64,
+            };
+
+            match entry_type {
+                TYPE_DIRECTORY => entries.insert_directory(&path)?,
+                TYPE_REGULAR | TYPE_REGULAR_OLD if !path.is_root() => entries.insert_file(path, TarEntry {
+                    offset: data_offset,
+                    size,
+                    modified,
+                })?,
+                // Links, devices and FIFOs
+                _ => (),
+            }
+        }
+
+        Ok(Self {
+            archive: Arc::new(Mutex::new(archive)),
+            entries,
+        })
+    }
+}
+
+impl<F: FileAccessor + 'static> FileSystemAccessor for TarFileSystem<F> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.entries.entry_type(path)
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let entry = self.entries.file(path)?;
+
+        Ok(Box::new(ArchiveFile::new(self.archive.clone(), entry.offset, entry.size)))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let entries = self.entries.list(path, |entry| (entry.size, Some(entry.timestamp())))?;
+
+        Ok(Box::new(DirectoryListing::new(entries, mode)))
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        Ok(self.entries.file(path)?.timestamp())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_file, path, read_path };
+
+    /// A ustar header, with `prefix` going in the field POSIX uses to split long names.
+    fn header(name: &str, prefix: &str, size: usize, entry_type: u8) -> Vec<u8> {
+        let mut header = vec![0; BLOCK_SIZE];
+
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(format!("{:011o}", size).as_bytes());
+        header[136..147].copy_from_slice(format!("{:011o}", 1_600_000_000).as_bytes());
+        header[156] = entry_type;
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[345..345 + prefix.len()].copy_from_slice(prefix.as_bytes());
+
+        update_checksum(&mut header);
+        header
+    }
+
+    fn update_checksum(header: &mut [u8]) {
+        let checksum: u32 = header.iter().enumerate().map(|(idx, &byte)| if (148..156).contains(&idx) { b' ' as u32 } else { byte as u32 }).sum();
+
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    }
+
+    /// A header followed by `data`, padded to a whole block.
+    fn entry(name: &str, entry_type: u8, data: &[u8]) -> Vec<u8> {
+        let mut entry = header(name, "", data.len(), entry_type);
+
+        entry.extend_from_slice(data);
+        entry.resize(BLOCK_SIZE + round_up(data.len()), 0);
+        entry
+    }
+
+    fn pax_record(key: &str, value: &str) -> String {
+        let body = format!(" {}={}\n", key, value);
+        let mut length = body.len() + 1;
+
+        // The length counts its own digits
+        while format!("{}{}", length, body).len() != length {
+            length += 1;
+        }
+
+        format!("{}{}", length, body)
+    }
+
+    fn build_tar(entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut archive = entries.concat();
+
+        archive.extend_from_slice(&[0; 2 * BLOCK_SIZE]);
+        archive
+    }
+
+    #[test]
+    fn reads_ustar_entries() {
+        let mut prefixed = header("file.txt", "some/long/prefix", 6, TYPE_REGULAR);
+        prefixed.extend_from_slice(b"nested");
+        prefixed.resize(2 * BLOCK_SIZE, 0);
+
+        let archive = build_tar(&[
+            entry("dir/", TYPE_DIRECTORY, b""),
+            entry("dir/a.txt", TYPE_REGULAR, b"hello"),
+            entry("old.txt", TYPE_REGULAR_OLD, &[1; 600]),
+            entry("link", b'2', b""),
+            prefixed,
+        ]);
+        let tar = TarFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(read_path(&tar, "dir/a.txt"), Ok(b"hello".to_vec()));
+        assert_eq!(read_path(&tar, "old.txt"), Ok(vec![1; 600]));
+        assert_eq!(read_path(&tar, "some/long/prefix/file.txt"), Ok(b"nested".to_vec()));
+        assert_eq!(tar.get_entry_type(&path("link")), Err(AccessorResult::PathNotFound));
+        assert_eq!(list_path(&tar, "dir"), Ok(vec![("a.txt".to_string(), Some(5))]));
+        assert_eq!(tar.get_file_time_stamp(&path("dir/a.txt")).unwrap().modified, 1_600_000_000);
+    }
+
+    #[test]
+    fn pax_headers_override_the_next_entry() {
+        let long_name = format!("{}/file.bin", "d".repeat(150));
+        let records = [pax_record("path", &long_name), pax_record("mtime", "1623760210.5"), pax_record("comment", "ignored")].concat();
+
+        let archive = build_tar(&[
+            entry("PaxHeaders/file.bin", TYPE_PAX, records.as_bytes()),
+            entry("file.bin", TYPE_REGULAR, b"data"),
+            entry("after.bin", TYPE_REGULAR, b"after"),
+        ]);
+        let tar = TarFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(read_path(&tar, &long_name), Ok(b"data".to_vec()));
+        assert_eq!(tar.get_file_time_stamp(&path(&long_name)).unwrap().modified, 1623760210);
+        assert_eq!(tar.get_entry_type(&path("file.bin")), Err(AccessorResult::PathNotFound));
+
+        // Only the next entry is affected
+        assert_eq!(read_path(&tar, "after.bin"), Ok(b"after".to_vec()));
+        assert_eq!(tar.get_file_time_stamp(&path("after.bin")).unwrap().modified, 1_600_000_000);
+    }
+
+    #[test]
+    fn pax_sizes_override_the_header() {
+        let records = pax_record("size", "700");
+
+        let mut file = header("big.bin", "", 0, TYPE_REGULAR);
+        file.extend_from_slice(&[2; 700]);
+        file.resize(BLOCK_SIZE + round_up(700), 0);
+
+        let archive = build_tar(&[entry("PaxHeaders/big.bin", TYPE_PAX, records.as_bytes()), file, entry("next", TYPE_REGULAR, b"next")]);
+        let tar = TarFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(read_path(&tar, "big.bin"), Ok(vec![2; 700]));
+        assert_eq!(read_path(&tar, "next"), Ok(b"next".to_vec()));
+    }
+
+    #[test]
+    fn gnu_long_names() {
+        let long_name = format!("{}/long.txt", "n".repeat(120));
+        let mut name = long_name.clone().into_bytes();
+        name.push(0);
+
+        let archive = build_tar(&[
+            entry("././@LongLink", TYPE_LONG_NAME, &name),
+            entry(&long_name[..100], TYPE_REGULAR, b"long"),
+        ]);
+        let tar = TarFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(read_path(&tar, &long_name), Ok(b"long".to_vec()));
+        assert_eq!(list_path(&tar, &long_name[..120]), Ok(vec![("long.txt".to_string(), Some(4))]));
+    }
+
+    #[test]
+    fn extension_headers_keep_their_own_size() {
+        let long_name = "x".repeat(300);
+        let records = pax_record("size", "4");
+
+        // The pax size is meant for the file, not for the long name header in between
+        let archive = build_tar(&[
+            entry("PaxHeaders/x", TYPE_PAX, records.as_bytes()),
+            entry("././@LongLink", TYPE_LONG_NAME, long_name.as_bytes()),
+            entry("x", TYPE_REGULAR, b"file"),
+        ]);
+        let tar = TarFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(read_path(&tar, &long_name), Ok(b"file".to_vec()));
+    }
+
+    #[test]
+    fn global_headers_are_skipped() {
+        let archive = build_tar(&[
+            entry("PaxHeaders/renamed", TYPE_PAX, pax_record("path", "renamed").as_bytes()),
+            entry("pax_global_header", TYPE_PAX_GLOBAL, [pax_record("path", "global"), pax_record("size", "1")].concat().as_bytes()),
+            entry("original", TYPE_REGULAR, b"contents"),
+            entry("other", TYPE_REGULAR, b"other"),
+        ]);
+        let tar = TarFileSystem::new(memory_file(&archive)).unwrap();
+
+        assert_eq!(read_path(&tar, "renamed"), Ok(b"contents".to_vec()));
+        assert_eq!(read_path(&tar, "other"), Ok(b"other".to_vec()));
+        assert_eq!(tar.get_entry_type(&path("global")), Err(AccessorResult::PathNotFound));
+        assert_eq!(tar.get_entry_type(&path("pax_global_header")), Err(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn malformed_archives_are_rejected() {
+        let archive = build_tar(&[entry("file", TYPE_REGULAR, &[3; 1000])]);
+
+        assert!(TarFileSystem::new(memory_file(&archive[..BLOCK_SIZE + 512])).is_err());
+
+        let mut corrupted = archive.clone();
+        corrupted[0] = b'g';
+        assert!(TarFileSystem::new(memory_file(&corrupted)).is_err());
+
+        // Base-256 size too large to be added to the offset
+        let mut huge = header("huge", "", 0, TYPE_REGULAR);
+        huge[124..136].copy_from_slice(&[0x80, 0, 0, 0, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]);
+        update_checksum(&mut huge);
+
+        assert_eq!(TarFileSystem::new(memory_file(&build_tar(&[huge]))).err(), Some(AccessorResult::Unexpected));
+
+        let records = pax_record("size", "18446744073709551615");
+        let overflowing = build_tar(&[entry("PaxHeaders/x", TYPE_PAX, records.as_bytes()), entry("x", TYPE_REGULAR, b"")]);
+
+        assert_eq!(TarFileSystem::new(memory_file(&overflowing)).err(), Some(AccessorResult::Unexpected));
+    }
+}
@@ -0,0 +1,372 @@
+use std::convert::TryInto;
+use std::sync::{Arc, Mutex};
+
+use skyline::nn;
+
+use super::{ read_exact, ArchiveFile, ArchiveIndex };
+use crate::{AccessorResult, DirectoryAccessor, DirectoryListing, FileAccessor, FileSystemAccessor, FileTimeStamp, FsEntryType, NnPath};
+
+const END_OF_CENTRAL_DIRECTORY_MAGIC: u32 = 0x06054b50;
+const CENTRAL_DIRECTORY_MAGIC: u32 = 0x02014b50;
+const LOCAL_HEADER_MAGIC: u32 = 0x04034b50;
+
+const END_OF_CENTRAL_DIRECTORY_SIZE: usize = 22;
+const CENTRAL_DIRECTORY_HEADER_SIZE: usize = 46;
+const LOCAL_HEADER_SIZE: usize = 30;
+const COMMENT_LENGTH_MAX: usize = 0xffff;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+const FLAG_ENCRYPTED: u16 = 1 << 0;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+/// Convert an MS-DOS date and time, as stored in ZIP headers, to a POSIX timestamp.
+fn dos_time_to_posix(date: u16, time: u16) -> i64 {
+    let year = 1980 + (date >> 9) as i64;
+    let month = ((date >> 5) & 0xf).max(1) as i64;
+    let day = (date & 0x1f).max(1) as i64;
+
+    // Days since the epoch, from http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+
+    days * 86400 + (time >> 11) as i64 * 3600 + ((time >> 5) & 0x3f) as i64 * 60 + (time & 0x1f) as i64 * 2
+}
+
+#[derive(Copy, Clone)]
+struct ZipEntry {
+    method: u16,
+    compressed_size: usize,
+    uncompressed_size: usize,
+    local_header_offset: usize,
+    modified: i64,
+}
+
+impl ZipEntry {
+    fn timestamp(&self) -> FileTimeStamp {
+        FileTimeStamp {
+            created: self.modified,
+            modified: self.modified,
+            accessed: self.modified,
+            is_local_time: true,
+        }
+    }
+}
+
+/// A read-only view of a ZIP archive, read from any `FileAccessor`.
+///
+/// Entries can be stored or compressed with deflate. Deflated files are decompressed whole into memory on their first read, stored files are read straight from the archive.
+/// Timestamps come from the archive and are in local time, ZIP64 and encrypted archives aren't supported.
+pub struct ZipFileSystem<F: FileAccessor> {
+    archive: Arc<Mutex<F>>,
+    entries: ArchiveIndex<ZipEntry>,
+}
+
+impl<F: FileAccessor> ZipFileSystem<F> {
+    /// Parse the central directory of the archive held by `archive`.
+    pub fn new(mut archive: F) -> Result<Self, AccessorResult> {
+        let archive_size = archive.get_size()?;
+
+        // The end of central directory record is at the very end, only followed by a comment of up to 64KiB
+        let tail_size = archive_size.min(END_OF_CENTRAL_DIRECTORY_SIZE + COMMENT_LENGTH_MAX);
+        let mut tail = vec![0; tail_size];
+        read_exact(&mut archive, &mut tail, archive_size - tail_size)?;
+
+        let end = (0..=tail_size.checked_sub(END_OF_CENTRAL_DIRECTORY_SIZE).ok_or(AccessorResult::Unexpected)?)
+            .rev()
+            .find(|&offset| u32_at(&tail, offset) == END_OF_CENTRAL_DIRECTORY_MAGIC)
+            .ok_or(AccessorResult::Unexpected)?;
+
+        let entry_count = u16_at(&tail, end + 10);
+        let directory_size = u32_at(&tail, end + 12);
+        let directory_offset = u32_at(&tail, end + 16);
+
+        if entry_count == 0xffff || directory_size == 0xffff_ffff || directory_offset == 0xffff_ffff {
+            return Err(AccessorResult::Unsupported);
+        }
+
+        // Both come from the archive, check them before allocating anything
+        let directory_end = (directory_offset as usize).checked_add(directory_size as usize).ok_or(AccessorResult::Unexpected)?;
+
+        if directory_end > archive_size {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let mut directory = vec![0; directory_size as usize];
+        read_exact(&mut archive, &mut directory, directory_offset as usize)?;
+
+        let mut entries = ArchiveIndex::new();
+        let mut offset = 0;
+
+        for _ in 0..entry_count {
+            let header = directory.get(offset..offset + CENTRAL_DIRECTORY_HEADER_SIZE).ok_or(AccessorResult::Unexpected)?;
+
+            if u32_at(header, 0) != CENTRAL_DIRECTORY_MAGIC {
+                return Err(AccessorResult::Unexpected);
+            }
+
+            let name_length = u16_at(header, 28) as usize;
+            let record_size = CENTRAL_DIRECTORY_HEADER_SIZE + name_length + u16_at(header, 30) as usize + u16_at(header, 32) as usize;
+
+            let name = directory.get(offset + CENTRAL_DIRECTORY_HEADER_SIZE..offset + CENTRAL_DIRECTORY_HEADER_SIZE + name_length).ok_or(AccessorResult::Unexpected)?;
+            let name = String::from_utf8_lossy(name);
+            let path = NnPath::new(&name)?;
+
+            if name.ends_with('/') {
+                entries.insert_directory(&path)?;
+            } else if !path.is_root() {
+                if u16_at(header, 8) & FLAG_ENCRYPTED != 0 {
+                    return Err(AccessorResult::Unsupported);
+                }
+
+                entries.insert_file(path, ZipEntry {
+                    method: u16_at(header, 10),
+                    compressed_size: u32_at(header, 20) as usize,
+                    uncompressed_size: u32_at(header, 24) as usize,
+                    local_header_offset: u32_at(header, 42) as usize,
+                    modified: dos_time_to_posix(u16_at(header, 14), u16_at(header, 12)),
+                })?;
+            }
+
+            offset += record_size;
+        }
+
+        Ok(Self {
+            archive: Arc::new(Mutex::new(archive)),
+            entries,
+        })
+    }
+
+    fn file_entry(&self, path: &NnPath) -> Result<ZipEntry, AccessorResult> {
+        self.entries.file(path).map(|entry| *entry)
+    }
+}
+
+impl<F: FileAccessor + 'static> FileSystemAccessor for ZipFileSystem<F> {
+    fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+        self.entries.entry_type(path)
+    }
+
+    fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+        let entry = self.file_entry(path)?;
+
+        if entry.method != METHOD_STORED && entry.method != METHOD_DEFLATED {
+            return Err(AccessorResult::Unsupported);
+        }
+
+        // The local header can have a different extra field than the central directory, its size has to be read from there
+        let mut header = [0; LOCAL_HEADER_SIZE];
+        read_exact(&mut *self.archive.lock().unwrap(), &mut header, entry.local_header_offset)?;
+
+        if u32_at(&header, 0) != LOCAL_HEADER_MAGIC {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let data_offset = entry.local_header_offset + LOCAL_HEADER_SIZE + u16_at(&header, 26) as usize + u16_at(&header, 28) as usize;
+
+        if entry.method == METHOD_STORED {
+            return Ok(Box::new(ArchiveFile::new(self.archive.clone(), data_offset, entry.uncompressed_size)));
+        }
+
+        Ok(Box::new(DeflatedFile {
+            archive: self.archive.clone(),
+            entry,
+            data_offset,
+            inflated: None,
+        }))
+    }
+
+    fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+        let entries = self.entries.list(path, |entry| (entry.uncompressed_size, Some(entry.timestamp())))?;
+
+        Ok(Box::new(DirectoryListing::new(entries, mode)))
+    }
+
+    fn get_file_time_stamp(&self, path: &NnPath) -> Result<FileTimeStamp, AccessorResult> {
+        Ok(self.file_entry(path)?.timestamp())
+    }
+}
+
+/// A deflated file opened through a `ZipFileSystem`.
+struct DeflatedFile<F: FileAccessor> {
+    archive: Arc<Mutex<F>>,
+    entry: ZipEntry,
+    data_offset: usize,
+    inflated: Option<Vec<u8>>,
+}
+
+impl<F: FileAccessor> DeflatedFile<F> {
+    fn inflated(&mut self) -> Result<&[u8], AccessorResult> {
+        if self.inflated.is_none() {
+            let mut compressed = vec![0; self.entry.compressed_size];
+            read_exact(&mut *self.archive.lock().unwrap(), &mut compressed, self.data_offset)?;
+
+            let data = miniz_oxide::inflate::decompress_to_vec_with_limit(&compressed, self.entry.uncompressed_size).map_err(|_| AccessorResult::Unexpected)?;
+
+            if data.len() != self.entry.uncompressed_size {
+                return Err(AccessorResult::Unexpected);
+            }
+
+            self.inflated = Some(data);
+        }
+
+        Ok(self.inflated.as_ref().unwrap())
+    }
+}
+
+impl<F: FileAccessor> FileAccessor for DeflatedFile<F> {
+    fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+        if offset >= self.entry.uncompressed_size {
+            return Ok(0);
+        }
+
+        let size = buffer.len().min(self.entry.uncompressed_size - offset);
+
+        buffer[..size].copy_from_slice(&self.inflated()?[offset..offset + size]);
+        Ok(size)
+    }
+
+    fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        Ok(self.entry.uncompressed_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backends::{ list_path, memory_file, path, read_path };
+
+    // 2021-06-15 12:30:10 in MS-DOS format
+    const DOS_DATE: u16 = (41 << 9) | (6 << 5) | 15;
+    const DOS_TIME: u16 = (12 << 11) | (30 << 5) | 5;
+
+    /// Build an archive holding `entries`, deflating the ones flagged so.
+    fn build_zip(entries: &[(&str, &[u8], bool)]) -> Vec<u8> {
+        let mut archive = Vec::new();
+        let mut directory = Vec::new();
+
+        for (name, data, deflate) in entries {
+            let (method, stored) = if *deflate {
+                (METHOD_DEFLATED, miniz_oxide::deflate::compress_to_vec(data, 6))
+            } else {
+                (METHOD_STORED, data.to_vec())
+            };
+
+            let mut fields = Vec::new();
+            fields.extend_from_slice(&0u16.to_le_bytes());
+            fields.extend_from_slice(&method.to_le_bytes());
+            fields.extend_from_slice(&DOS_TIME.to_le_bytes());
+            fields.extend_from_slice(&DOS_DATE.to_le_bytes());
+            fields.extend_from_slice(&0u32.to_le_bytes());
+            fields.extend_from_slice(&(stored.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            fields.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            fields.extend_from_slice(&0u16.to_le_bytes());
+
+            directory.extend_from_slice(&CENTRAL_DIRECTORY_MAGIC.to_le_bytes());
+            directory.extend_from_slice(&[20, 0, 20, 0]);
+            directory.extend_from_slice(&fields);
+            // Comment length, disk number, internal and external attributes
+            directory.extend_from_slice(&[0; 10]);
+            directory.extend_from_slice(&(archive.len() as u32).to_le_bytes());
+            directory.extend_from_slice(name.as_bytes());
+
+            archive.extend_from_slice(&LOCAL_HEADER_MAGIC.to_le_bytes());
+            archive.extend_from_slice(&[20, 0]);
+            archive.extend_from_slice(&fields);
+            archive.extend_from_slice(name.as_bytes());
+            archive.extend_from_slice(&stored);
+        }
+
+        let directory_offset = archive.len() as u32;
+        archive.extend_from_slice(&directory);
+
+        archive.extend_from_slice(&END_OF_CENTRAL_DIRECTORY_MAGIC.to_le_bytes());
+        archive.extend_from_slice(&[0; 4]);
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+        archive.extend_from_slice(&(directory.len() as u32).to_le_bytes());
+        archive.extend_from_slice(&directory_offset.to_le_bytes());
+        archive.extend_from_slice(&0u16.to_le_bytes());
+        archive
+    }
+
+    fn sample() -> ZipFileSystem<Box<dyn FileAccessor>> {
+        let repeated = b"deflate me ".repeat(64);
+        let archive = build_zip(&[
+            ("empty/", b"", false),
+            ("dir/stored.txt", b"stored", false),
+            ("dir/deflated.txt", &repeated, true),
+        ]);
+
+        ZipFileSystem::new(memory_file(&archive)).unwrap()
+    }
+
+    #[test]
+    fn reads_stored_and_deflated_entries() {
+        let zip = sample();
+
+        assert_eq!(read_path(&zip, "dir/stored.txt").unwrap(), b"stored");
+        assert_eq!(read_path(&zip, "dir/deflated.txt").unwrap(), b"deflate me ".repeat(64));
+        assert_eq!(read_path(&zip, "dir/missing").err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn lists_implicit_and_explicit_directories() {
+        let zip = sample();
+
+        assert_eq!(zip.get_entry_type(&path("dir")), Ok(FsEntryType::Directory));
+        assert_eq!(list_path(&zip, "").unwrap(), vec![("dir".to_owned(), None), ("empty".to_owned(), None)]);
+        assert_eq!(list_path(&zip, "dir").unwrap(), vec![("deflated.txt".to_owned(), Some(704)), ("stored.txt".to_owned(), Some(6))]);
+        assert!(list_path(&zip, "empty").unwrap().is_empty());
+    }
+
+    #[test]
+    fn converts_dos_timestamps() {
+        let zip = sample();
+        let timestamp = zip.get_file_time_stamp(&path("dir/stored.txt")).unwrap();
+
+        assert_eq!(timestamp.modified, 1623760210);
+        assert!(timestamp.is_local_time);
+    }
+
+    #[test]
+    fn rejects_truncated_archives() {
+        let archive = build_zip(&[("file", b"data", false)]);
+
+        assert!(ZipFileSystem::new(memory_file(&archive[..archive.len() - 4])).is_err());
+        assert!(ZipFileSystem::new(memory_file(b"not a zip")).is_err());
+    }
+
+    #[test]
+    fn rejects_central_directories_past_the_end() {
+        let archive = build_zip(&[("file", b"data", false)]);
+        let end = archive.len() - END_OF_CENTRAL_DIRECTORY_SIZE;
+
+        let mut oversized = archive.clone();
+        oversized[end + 12..end + 16].copy_from_slice(&0xffff_fff0u32.to_le_bytes());
+        assert_eq!(ZipFileSystem::new(memory_file(&oversized)).err(), Some(AccessorResult::Unexpected));
+
+        let mut misplaced = archive.clone();
+        misplaced[end + 16..end + 20].copy_from_slice(&(archive.len() as u32).to_le_bytes());
+        assert_eq!(ZipFileSystem::new(memory_file(&misplaced)).err(), Some(AccessorResult::Unexpected));
+
+        // The record survives, but the directory it points to was cut from the archive
+        let directory_offset = u32_at(&archive, end + 16) as usize;
+        let mut truncated = archive[..directory_offset].to_vec();
+        truncated.extend_from_slice(&archive[end..]);
+        assert_eq!(ZipFileSystem::new(memory_file(&truncated)).err(), Some(AccessorResult::Unexpected));
+    }
+}
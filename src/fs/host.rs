@@ -0,0 +1,657 @@
+//! Host-side stand-in for the parts of `nn::fs` this crate links against.
+//!
+//! Enabled with the `host` feature. Allocation, mount name checks and registration are serviced
+//! by a per-thread mount table instead of the SDK, and the functions below drive the registered
+//! vtables by `"mount:/path"` the same way the SDK does, so accessors can be exercised with
+//! `cargo test`. The mount table is thread-local so tests running in parallel don't see each
+//! other's mounts.
+
+use std::alloc::{self, Layout};
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::path::PathBuf;
+
+use skyline::nn;
+
+use crate::{AccessorResult, DAccessor, DirectoryEntry, DirectoryEntryType, FAccessor, FileTimeStamp, FileTimeStampRaw, FsAccessor, FsEntryType, OperateRangeId, QueryEntryId, QueryRangeInfo};
+
+// Every allocation is prefixed with its size so `deallocate` can rebuild the layout
+const HEADER_SIZE: usize = 0x10;
+
+const MOUNT_NAME_LENGTH_MAX: usize = 15;
+
+const RESULT_MOUNT_NAME_ALREADY_EXISTS: u32 = 0x7802;
+const RESULT_INVALID_MOUNT_NAME: u32 = 0x2f6202;
+
+thread_local! {
+    static MOUNTS: RefCell<HashMap<String, *mut FsAccessor>> = RefCell::new(HashMap::new());
+    static LIVE_ALLOCATIONS: Cell<usize> = Cell::new(0);
+    static REGISTRATION_FAILURE: Cell<u32> = Cell::new(0);
+}
+
+/// Number of allocations made through `fs::detail::alloc` on this thread that haven't been freed yet.
+///
+/// Every accessor handed to the SDK is allocated this way, so this drops back to its previous value once every file, directory and mount opened in between has been released.
+pub fn live_allocations() -> usize {
+    LIVE_ALLOCATIONS.with(|count| count.get())
+}
+
+/// Make the next registration on this thread fail with `result` after its mount name was accepted, like the SDK can when it runs out of resources.
+pub fn fail_next_registration(result: u32) {
+    REGISTRATION_FAILURE.with(|failure| failure.set(result));
+}
+
+pub(crate) unsafe fn allocate(size: usize) -> *mut u8 {
+    let layout = Layout::from_size_align_unchecked(size + HEADER_SIZE, HEADER_SIZE);
+    let ptr = alloc::alloc(layout);
+
+    if ptr.is_null() {
+        return ptr;
+    }
+
+    (ptr as *mut usize).write(size);
+    LIVE_ALLOCATIONS.with(|count| count.set(count.get() + 1));
+
+    ptr.add(HEADER_SIZE)
+}
+
+pub(crate) unsafe fn deallocate(ptr: *mut u8) {
+    if ptr.is_null() {
+        return;
+    }
+
+    let base = ptr.sub(HEADER_SIZE);
+    let size = (base as *mut usize).read();
+    LIVE_ALLOCATIONS.with(|count| count.set(count.get().saturating_sub(1)));
+
+    alloc::dealloc(base, Layout::from_size_align_unchecked(size + HEADER_SIZE, HEADER_SIZE));
+}
+
+pub(crate) unsafe fn check_mount_name(name: *const u8) -> u32 {
+    let name = match CStr::from_ptr(name as _).to_str() {
+        Ok(name) => name,
+        Err(_) => return RESULT_INVALID_MOUNT_NAME,
+    };
+
+    if name.is_empty() || name.len() > MOUNT_NAME_LENGTH_MAX || name.contains(|c| c == ':' || c == '/') {
+        RESULT_INVALID_MOUNT_NAME
+    } else if MOUNTS.with(|mounts| mounts.borrow().contains_key(name)) {
+        RESULT_MOUNT_NAME_ALREADY_EXISTS
+    } else {
+        0
+    }
+}
+
+pub(crate) unsafe fn register_fsa(mount_name: *const u8, unique_fs_ptr: *mut *mut u8) -> u32 {
+    let result = check_mount_name(mount_name);
+
+    if result != 0 {
+        return result;
+    }
+
+    let failure = REGISTRATION_FAILURE.with(|failure| failure.replace(0));
+
+    if failure != 0 {
+        return failure;
+    }
+
+    let name = CStr::from_ptr(mount_name as _).to_str().unwrap().to_owned();
+
+    // The SDK takes ownership of the unique_ptr, leaving the caller's empty
+    let accessor = std::ptr::replace(unique_fs_ptr, std::ptr::null_mut()) as *mut FsAccessor;
+
+    MOUNTS.with(|mounts| mounts.borrow_mut().insert(name, accessor));
+    0
+}
+
+pub(crate) unsafe fn unmount_fs(mount_name: *const u8) {
+    if let Ok(name) = CStr::from_ptr(mount_name as _).to_str() {
+        let _ = unmount(name);
+    }
+}
+
+fn to_result(result: AccessorResult) -> Result<(), AccessorResult> {
+    match result {
+        AccessorResult::Success => Ok(()),
+        e => Err(e),
+    }
+}
+
+fn split_mount_name(path: &str) -> Result<(&str, &str), AccessorResult> {
+    match path.find(':') {
+        Some(idx) => Ok((&path[..idx], &path[idx + 1..])),
+        None => Err(AccessorResult::PathNotFound),
+    }
+}
+
+/// Split a `"mount:/path"` string into the registered filesystem and the path handed to its vtable.
+fn resolve(path: &str) -> Result<(&'static mut FsAccessor, CString), AccessorResult> {
+    let (mount_name, path) = split_mount_name(path)?;
+
+    let accessor = MOUNTS.with(|mounts| mounts.borrow().get(mount_name).copied()).ok_or(AccessorResult::PathNotFound)?;
+    let path = CString::new(path).map_err(|_| AccessorResult::PathNotFound)?;
+
+    // SAFETY: The accessor stays registered until `unmount` is called for it on this thread
+    Ok((unsafe { &mut *accessor }, path))
+}
+
+/// Unregister a mount point and destroy its filesystem accessor, like `nn::fs::Unmount`.
+pub fn unmount<S: AsRef<str>>(mount_name: S) -> Result<(), AccessorResult> {
+    let accessor = MOUNTS.with(|mounts| mounts.borrow_mut().remove(mount_name.as_ref())).ok_or(AccessorResult::PathNotFound)?;
+
+    unsafe { ((*accessor).vtable.deleter)(&mut *accessor) };
+    Ok(())
+}
+
+pub fn get_entry_type(path: &str) -> Result<FsEntryType, AccessorResult> {
+    let (fs, path) = resolve(path)?;
+    let mut entry_type = FsEntryType::File;
+
+    to_result((fs.vtable.get_entry_type)(fs, &mut entry_type, path.as_ptr() as _))?;
+    Ok(entry_type)
+}
+
+pub fn create_file(path: &str, size: usize) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.create_file)(fs, path.as_ptr() as _, size, 0))
+}
+
+pub fn delete_file(path: &str) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.delete_file)(fs, path.as_ptr() as _))
+}
+
+pub fn create_directory(path: &str) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.create_directory)(fs, path.as_ptr() as _))
+}
+
+pub fn delete_directory(path: &str) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.delete_directory)(fs, path.as_ptr() as _))
+}
+
+pub fn delete_directory_recursively(path: &str) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.delete_directory_recursively)(fs, path.as_ptr() as _))
+}
+
+pub fn clean_directory_recursively(path: &str) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.clean_directory_recursively)(fs, path.as_ptr() as _))
+}
+
+pub fn get_free_space_size(path: &str) -> Result<usize, AccessorResult> {
+    let (fs, path) = resolve(path)?;
+    let mut size = 0;
+
+    to_result((fs.vtable.get_free_space_size)(fs, &mut size, path.as_ptr() as _))?;
+    Ok(size)
+}
+
+pub fn get_total_space_size(path: &str) -> Result<usize, AccessorResult> {
+    let (fs, path) = resolve(path)?;
+    let mut size = 0;
+
+    to_result((fs.vtable.get_total_space_size)(fs, &mut size, path.as_ptr() as _))?;
+    Ok(size)
+}
+
+pub fn get_file_time_stamp(path: &str) -> Result<FileTimeStamp, AccessorResult> {
+    let (fs, path) = resolve(path)?;
+    let mut timestamp = FileTimeStampRaw::default();
+
+    to_result((fs.vtable.get_file_time_stamp_raw)(fs, &mut timestamp, path.as_ptr() as _))?;
+    Ok(timestamp.into())
+}
+
+pub fn query_entry(path: &str, output: &mut [u8], input: &[u8], query_id: QueryEntryId) -> Result<(), AccessorResult> {
+    let (fs, path) = resolve(path)?;
+
+    to_result((fs.vtable.query_entry)(fs, output.as_mut_ptr(), output.len(), input.as_ptr(), input.len(), query_id as u32, path.as_ptr() as _))
+}
+
+pub fn commit<S: AsRef<str>>(mount_name: S) -> Result<(), AccessorResult> {
+    let (fs, _) = resolve(&[mount_name.as_ref(), ":/"].concat())?;
+
+    to_result((fs.vtable.commit)(fs))
+}
+
+pub fn commit_provisionally<S: AsRef<str>>(mount_name: S, counter: u64) -> Result<(), AccessorResult> {
+    let (fs, _) = resolve(&[mount_name.as_ref(), ":/"].concat())?;
+
+    to_result((fs.vtable.commit_provisionally)(fs, counter))
+}
+
+pub fn rollback<S: AsRef<str>>(mount_name: S) -> Result<(), AccessorResult> {
+    let (fs, _) = resolve(&[mount_name.as_ref(), ":/"].concat())?;
+
+    to_result((fs.vtable.rollback)(fs))
+}
+
+/// Resolve both sides of a rename, which the SDK only allows within a single mount.
+fn resolve_rename(path: &str, new_path: &str) -> Result<(&'static mut FsAccessor, CString, CString), AccessorResult> {
+    if split_mount_name(path)?.0 != split_mount_name(new_path)?.0 {
+        return Err(AccessorResult::Unsupported);
+    }
+
+    let (fs, path) = resolve(path)?;
+    let new_path = CString::new(split_mount_name(new_path)?.1).map_err(|_| AccessorResult::PathNotFound)?;
+
+    Ok((fs, path, new_path))
+}
+
+pub fn rename_file(path: &str, new_path: &str) -> Result<(), AccessorResult> {
+    let (fs, path, new_path) = resolve_rename(path, new_path)?;
+
+    to_result((fs.vtable.rename_file)(fs, path.as_ptr() as _, new_path.as_ptr() as _))
+}
+
+pub fn rename_directory(path: &str, new_path: &str) -> Result<(), AccessorResult> {
+    let (fs, path, new_path) = resolve_rename(path, new_path)?;
+
+    to_result((fs.vtable.rename_directory)(fs, path.as_ptr() as _, new_path.as_ptr() as _))
+}
+
+pub fn open_file(path: &str, mode: nn::fs::OpenMode) -> Result<File, AccessorResult> {
+    let (fs, path) = resolve(path)?;
+    let mut accessor: *mut FAccessor = std::ptr::null_mut();
+
+    to_result((fs.vtable.open_file)(fs, &mut accessor, path.as_ptr() as _, mode))?;
+
+    if accessor.is_null() {
+        Err(AccessorResult::Unexpected)
+    } else {
+        Ok(File(accessor))
+    }
+}
+
+pub fn open_directory(path: &str, mode: nn::fs::OpenDirectoryMode) -> Result<Directory, AccessorResult> {
+    let (fs, path) = resolve(path)?;
+    let mut accessor: *mut DAccessor = std::ptr::null_mut();
+
+    to_result((fs.vtable.open_directory)(fs, &mut accessor, path.as_ptr() as _, mode))?;
+
+    if accessor.is_null() {
+        Err(AccessorResult::Unexpected)
+    } else {
+        Ok(Directory(accessor))
+    }
+}
+
+/// An open file, owned the same way the SDK owns the `std::unique_ptr` returned by `OpenFile`.
+pub struct File(*mut FAccessor);
+
+impl File {
+    pub fn read(&mut self, offset: usize, buffer: &mut [u8]) -> Result<usize, AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+        let mut read_size = 0;
+
+        to_result((accessor.vtable.read)(accessor, &mut read_size, offset, buffer.as_mut_ptr(), buffer.len(), 0))?;
+        Ok(read_size)
+    }
+
+    pub fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+        let option: nn::fs::WriteOption = unsafe { std::mem::zeroed() };
+
+        to_result((accessor.vtable.write)(accessor, offset, data.as_ptr(), data.len(), &option))
+    }
+
+    pub fn flush(&mut self) -> Result<(), AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+
+        to_result((accessor.vtable.flush)(accessor))
+    }
+
+    pub fn set_size(&mut self, size: usize) -> Result<(), AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+
+        to_result((accessor.vtable.set_size)(accessor, size))
+    }
+
+    pub fn get_size(&mut self) -> Result<usize, AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+        let mut size = 0;
+
+        to_result((accessor.vtable.get_size)(accessor, &mut size))?;
+        Ok(size)
+    }
+
+    /// `offset` and `size` are signed like the SDK's, so tests can pass ranges the accessors have to reject.
+    pub fn operate_range(&mut self, operation: OperateRangeId, offset: i64, size: i64) -> Result<QueryRangeInfo, AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+        let mut info = QueryRangeInfo::default();
+
+        to_result((accessor.vtable.operate_range)(accessor, &mut info as *mut _ as _, std::mem::size_of::<QueryRangeInfo>(), operation as u32, offset, size, std::ptr::null(), 0))?;
+        Ok(info)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        unsafe { ((*self.0).vtable.deleter)(&mut *self.0) }
+    }
+}
+
+/// An open directory, owned the same way the SDK owns the `std::unique_ptr` returned by `OpenDirectory`.
+pub struct Directory(*mut DAccessor);
+
+impl Directory {
+    /// Read up to `count` entries, converting them back from `nn::fs::DirectoryEntry`.
+    pub fn read(&mut self, count: usize) -> Result<Vec<DirectoryEntry>, AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+        let mut buffer: Vec<nn::fs::DirectoryEntry> = (0..count).map(|_| unsafe { std::mem::zeroed() }).collect();
+        let mut read_count = 0;
+
+        to_result((accessor.vtable.read)(accessor, &mut read_count, buffer.as_mut_ptr(), buffer.len()))?;
+
+        Ok(buffer[..read_count as usize].iter().map(|entry| {
+            let name_len = entry.name.iter().position(|&c| c == 0).unwrap_or(entry.name.len());
+            let name = entry.name[..name_len].iter().map(|&c| c as u8).collect::<Vec<u8>>();
+
+            DirectoryEntry {
+                path: PathBuf::from(String::from_utf8_lossy(&name).into_owned()),
+                ty: match entry.type_ {
+                    0 => DirectoryEntryType::Directory,
+                    _ => DirectoryEntryType::File(entry.fileSize),
+                },
+                timestamp: None,
+            }
+        }).collect())
+    }
+
+    pub fn get_entry_count(&mut self) -> Result<usize, AccessorResult> {
+        let accessor = unsafe { &mut *self.0 };
+        let mut count = 0;
+
+        to_result((accessor.vtable.get_entry_count)(accessor, &mut count))?;
+        Ok(count as usize)
+    }
+}
+
+impl Drop for Directory {
+    fn drop(&mut self) {
+        unsafe { ((*self.0).vtable.deleter)(&mut *self.0) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{ mount, MountError, DirectoryAccessor, DirectoryListing, FileAccessor, FileSystemAccessor, NnPath };
+
+    const CONTENTS: &[u8] = b"hello";
+
+    struct StaticFile;
+
+    impl FileAccessor for StaticFile {
+        fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+            let data = CONTENTS.get(offset..).unwrap_or_default();
+            let size = data.len().min(buffer.len());
+
+            buffer[..size].copy_from_slice(&data[..size]);
+            Ok(size)
+        }
+
+        fn get_size(&mut self) -> Result<usize, AccessorResult> {
+            Ok(CONTENTS.len())
+        }
+
+        fn operate_range(&mut self, operation: OperateRangeId, offset: usize, size: usize, info: &mut QueryRangeInfo) -> AccessorResult {
+            AccessorResult::Success
+        }
+    }
+
+    /// A single file at the root, `hello.txt`.
+    struct SingleFileSystem;
+
+    impl FileSystemAccessor for SingleFileSystem {
+        fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+            match path.as_str() {
+                "" => Ok(FsEntryType::Directory),
+                "hello.txt" => Ok(FsEntryType::File),
+                _ => Err(AccessorResult::PathNotFound),
+            }
+        }
+
+        fn open_file(&self, path: &NnPath, _mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+            match path.as_str() {
+                "hello.txt" => Ok(Box::new(StaticFile)),
+                _ => Err(AccessorResult::PathNotFound),
+            }
+        }
+
+        fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+            if !path.as_str().is_empty() {
+                return Err(AccessorResult::PathNotFound);
+            }
+
+            let entry = DirectoryEntry {
+                path: PathBuf::from("hello.txt"),
+                ty: DirectoryEntryType::File(CONTENTS.len() as i64),
+                timestamp: None,
+            };
+
+            Ok(Box::new(DirectoryListing::new(vec![entry], mode)))
+        }
+    }
+
+    /// Lists the same entries for every directory.
+    struct ListingFileSystem(Vec<DirectoryEntry>);
+
+    impl FileSystemAccessor for ListingFileSystem {
+        fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+            Ok(FsEntryType::Directory)
+        }
+
+        fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+            Err(AccessorResult::PathNotFound)
+        }
+
+        fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+            Ok(Box::new(DirectoryListing::new(self.0.clone(), mode)))
+        }
+    }
+
+    /// Hands out files and directories that count how many times they were dropped.
+    struct CountingFileSystem {
+        drops: Rc<Cell<usize>>,
+    }
+
+    struct CountingFile(Rc<Cell<usize>>);
+
+    impl Drop for CountingFile {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    impl FileAccessor for CountingFile {
+        fn read(&mut self, buffer: &mut [u8], offset: usize) -> Result<usize, AccessorResult> {
+            Ok(0)
+        }
+
+        fn get_size(&mut self) -> Result<usize, AccessorResult> {
+            Ok(0)
+        }
+    }
+
+    struct CountingDirectory(Rc<Cell<usize>>);
+
+    impl Drop for CountingDirectory {
+        fn drop(&mut self) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    impl DirectoryAccessor for CountingDirectory {
+        fn read(&mut self, buffer: &mut [DirectoryEntry]) -> Result<usize, AccessorResult> {
+            Ok(0)
+        }
+
+        fn get_entry_count(&mut self) -> Result<usize, AccessorResult> {
+            Ok(0)
+        }
+    }
+
+    impl Drop for CountingFileSystem {
+        fn drop(&mut self) {
+            self.drops.set(self.drops.get() + 1);
+        }
+    }
+
+    impl FileSystemAccessor for CountingFileSystem {
+        fn get_entry_type(&self, path: &NnPath) -> Result<FsEntryType, AccessorResult> {
+            Ok(FsEntryType::File)
+        }
+
+        fn open_file(&self, path: &NnPath, mode: nn::fs::OpenMode) -> Result<Box<dyn FileAccessor>, AccessorResult> {
+            Ok(Box::new(CountingFile(self.drops.clone())))
+        }
+
+        fn open_directory(&self, path: &NnPath, mode: nn::fs::OpenDirectoryMode) -> Result<Box<dyn DirectoryAccessor>, AccessorResult> {
+            Ok(Box::new(CountingDirectory(self.drops.clone())))
+        }
+    }
+
+    #[test]
+    fn closing_drops_the_accessors() {
+        let drops = Rc::new(Cell::new(0));
+        let handle = mount("leak", CountingFileSystem { drops: drops.clone() }).unwrap();
+        assert_eq!(live_allocations(), 1);
+
+        let file = open_file("leak:/file", nn::fs::OpenMode_OpenMode_Read).unwrap();
+        let directory = open_directory("leak:/", nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).unwrap();
+        assert_eq!(live_allocations(), 3);
+        assert_eq!(drops.get(), 0);
+
+        drop(file);
+        assert_eq!(drops.get(), 1);
+
+        drop(directory);
+        assert_eq!(drops.get(), 2);
+        assert_eq!(live_allocations(), 1);
+
+        handle.unmount();
+        assert_eq!(drops.get(), 3);
+        assert_eq!(live_allocations(), 0);
+    }
+
+    #[test]
+    fn unavailable_mount_names_drop_the_accessor() {
+        let drops = Rc::new(Cell::new(0));
+        let _handle = mount("taken", SingleFileSystem).unwrap();
+
+        assert!(mount("taken", CountingFileSystem { drops: drops.clone() }).is_err());
+        assert_eq!(drops.get(), 1);
+        assert_eq!(live_allocations(), 1);
+    }
+
+    #[test]
+    fn failed_registrations_release_the_accessor() {
+        let drops = Rc::new(Cell::new(0));
+
+        fail_next_registration(0x1234);
+
+        match mount("failing", CountingFileSystem { drops: drops.clone() }) {
+            Err(MountError::RegistrationFailed(0x1234)) => (),
+            _ => panic!("the registration should have failed"),
+        }
+
+        assert_eq!(drops.get(), 1);
+        assert_eq!(live_allocations(), 0);
+        assert_eq!(get_entry_type("failing:/file").err(), Some(AccessorResult::PathNotFound));
+
+        // Only the next registration fails
+        mount("failing", SingleFileSystem).unwrap().unmount();
+        assert_eq!(live_allocations(), 0);
+    }
+
+    #[test]
+    fn drives_the_vtables() {
+        let handle = mount("host", SingleFileSystem).unwrap();
+
+        assert_eq!(get_entry_type("host:/hello.txt").unwrap(), FsEntryType::File);
+        assert_eq!(get_entry_type("host:/missing").err(), Some(AccessorResult::PathNotFound));
+
+        let mut file = open_file("host:/hello.txt", nn::fs::OpenMode_OpenMode_Read).unwrap();
+        let mut buffer = [0; 8];
+
+        assert_eq!(file.get_size().unwrap(), CONTENTS.len());
+        assert_eq!(file.read(1, &mut buffer).unwrap(), 4);
+        assert_eq!(&buffer[..4], b"ello");
+        assert!(file.operate_range(OperateRangeId::Invalidate, 0, 5).is_ok());
+        drop(file);
+
+        let mut directory = open_directory("host:/", nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).unwrap();
+        assert_eq!(directory.get_entry_count().unwrap(), 1);
+
+        let entries = directory.read(4).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, Path::new("hello.txt"));
+        assert!(matches!(entries[0].ty, DirectoryEntryType::File(5)));
+
+        // Only files were listed, so a directory-only listing is empty
+        let mut directory = open_directory("host:/", nn::fs::OpenDirectoryMode_OpenDirectoryMode_Directory).unwrap();
+        assert!(directory.read(4).unwrap().is_empty());
+        drop(directory);
+
+        handle.unmount();
+        assert_eq!(get_entry_type("host:/hello.txt").err(), Some(AccessorResult::PathNotFound));
+        assert_eq!(unmount("host").err(), Some(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn long_names_are_truncated() {
+        let name_max = unsafe { std::mem::zeroed::<nn::fs::DirectoryEntry>() }.name.len() - 1;
+        let names = vec!["a".repeat(2000), format!("{}é", "b".repeat(name_max - 1)), "c".repeat(name_max), "short".to_owned()];
+
+        let entries = names.iter().map(|name| DirectoryEntry {
+            path: PathBuf::from(name),
+            ty: DirectoryEntryType::File(0),
+            timestamp: None,
+        }).collect();
+
+        let _handle = mount("names", ListingFileSystem(entries)).unwrap();
+        let mut directory = open_directory("names:/", nn::fs::OpenDirectoryMode_OpenDirectoryMode_All).unwrap();
+        let listed: Vec<_> = directory.read(8).unwrap().into_iter().map(|entry| entry.path).collect();
+
+        // The two byte character doesn't fit, and isn't cut in half either
+        assert_eq!(listed, vec![
+            PathBuf::from("a".repeat(name_max)),
+            PathBuf::from("b".repeat(name_max - 1)),
+            PathBuf::from("c".repeat(name_max)),
+            PathBuf::from("short"),
+        ]);
+    }
+
+    #[test]
+    fn negative_ranges_are_rejected() {
+        let _handle = mount("range", SingleFileSystem).unwrap();
+        let mut file = open_file("range:/hello.txt", nn::fs::OpenMode_OpenMode_Read).unwrap();
+
+        assert_eq!(file.operate_range(OperateRangeId::Invalidate, -1, 5).err(), Some(AccessorResult::Unexpected));
+        assert_eq!(file.operate_range(OperateRangeId::QueryRange, 0, -5).err(), Some(AccessorResult::Unexpected));
+        assert!(file.operate_range(OperateRangeId::QueryRange, 0, 5).is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_and_duplicate_mount_names() {
+        let _handle = mount("taken", SingleFileSystem).unwrap();
+
+        assert!(mount("taken", SingleFileSystem).is_err());
+        assert!(mount("bad:name", SingleFileSystem).is_err());
+        assert!(mount("", SingleFileSystem).is_err());
+    }
+}
@@ -3,13 +3,89 @@
 mod accessors;
 pub use accessors::*;
 
+mod path;
+pub use path::{ NnPath, PATH_LENGTH_MAX };
+
+mod mount;
+pub use mount::{ mount, MountError, MountHandle };
+
+pub mod backends;
+
 #[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum FsEntryType {
     Directory = 0,
     File = 1
 }
 
+/// Queries that can be sent to a filesystem through `nn::fs::QueryEntry`.
+#[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum QueryEntryId {
+    SetConcatenationFileAttribute = 0,
+    UpdateMac = 1,
+    IsSignedSystemPartitionOnSdCardValid = 2,
+    QueryUnpreparedFileInformation = 3,
+}
+
+impl QueryEntryId {
+    pub fn from_raw(query_id: u32) -> Option<Self> {
+        match query_id {
+            0 => Some(QueryEntryId::SetConcatenationFileAttribute),
+            1 => Some(QueryEntryId::UpdateMac),
+            2 => Some(QueryEntryId::IsSignedSystemPartitionOnSdCardValid),
+            3 => Some(QueryEntryId::QueryUnpreparedFileInformation),
+            _ => None,
+        }
+    }
+}
+
+/// Creation, modification and access times of an entry, as POSIX timestamps.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct FileTimeStamp {
+    pub created: i64,
+    pub modified: i64,
+    pub accessed: i64,
+    /// The timestamps are in local time rather than UTC
+    pub is_local_time: bool,
+}
+
+// Layout of nn::fs::FileTimeStampRaw
+#[repr(C)]
+#[derive(Debug, Copy, Clone, Default)]
+pub(crate) struct FileTimeStampRaw {
+    pub create: i64,
+    pub modify: i64,
+    pub access: i64,
+    pub is_local_time: bool,
+    pub padding: [u8; 7],
+}
+
+impl From<FileTimeStamp> for FileTimeStampRaw {
+    fn from(timestamp: FileTimeStamp) -> Self {
+        Self {
+            create: timestamp.created,
+            modify: timestamp.modified,
+            access: timestamp.accessed,
+            is_local_time: timestamp.is_local_time,
+            padding: [0; 7],
+        }
+    }
+}
+
+impl From<FileTimeStampRaw> for FileTimeStamp {
+    fn from(raw: FileTimeStampRaw) -> Self {
+        Self {
+            created: raw.create,
+            modified: raw.modify,
+            accessed: raw.access,
+            is_local_time: raw.is_local_time,
+        }
+    }
+}
+
 #[repr(u32)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
 pub enum AccessorResult {
     Success = 0,
     PathNotFound = 0x202,
@@ -23,10 +99,34 @@ pub enum AccessorResult {
 }
 
 pub mod fs {
+    #[cfg(feature = "host")]
+    pub mod host;
+
+    #[cfg(not(feature = "host"))]
+    use skyline::libc::c_char;
+    #[cfg(feature = "host")]
+    use host::unmount_fs;
+
+    #[cfg(not(feature = "host"))]
+    extern "C" {
+        #[link_name = "\u{1}_ZN2nn2fs7UnmountEPKc"]
+        fn unmount_fs(mount_name: *const c_char);
+    }
+
+    pub fn unmount<S: AsRef<str>>(mount_name: S) {
+        unsafe {
+            unmount_fs([mount_name.as_ref(), "\0"].concat().as_ptr())
+        }
+    }
+
     pub mod detail {
+        #[cfg(not(feature = "host"))]
         use skyline::libc::{c_char, c_void};
+        #[cfg(feature = "host")]
+        use super::host::{allocate, deallocate, check_mount_name};
         use std::mem::MaybeUninit;
 
+        #[cfg(not(feature = "host"))]
         extern "C" {
             #[link_name = "\u{1}_ZN2nn2fs6detail8AllocateEm"]
             fn allocate(size: usize) -> *mut c_void;
@@ -46,7 +146,7 @@ pub mod fs {
 
         pub fn free<T>(ptr: *mut T) {
             unsafe {
-                deallocate(ptr as *mut c_void)
+                deallocate(ptr as _)
             }
         }
 
@@ -58,29 +158,22 @@ pub mod fs {
     }
 
     pub mod fsa {
+        #[cfg(not(feature = "host"))]
         use skyline::libc::c_char;
+        #[cfg(feature = "host")]
+        use super::host::register_fsa;
 
+        #[cfg(not(feature = "host"))]
         extern "C" {
             #[link_name = "\u{1}_ZN2nn2fs3fsa8RegisterEPKcONSt3__110unique_ptrINS1_11IFileSystemENS4_14default_deleteIS6_EEEE"]
             fn register_fsa(mount_name: *const c_char, unique_fs_ptr: *mut *mut u8) -> u32;
         }
 
-        pub fn register<S: AsRef<str>, T>(mount_name: S, fsa: *mut T) -> u32 {
+        /// Hand `fsa` over to the SDK, which resets it to null once it has taken ownership.
+        pub fn register<S: AsRef<str>, T>(mount_name: S, fsa: &mut *mut T) -> u32 {
             unsafe {
-                register_fsa([mount_name.as_ref(), "\0"].concat().as_ptr(), &mut (fsa as *mut u8))
+                register_fsa([mount_name.as_ref(), "\0"].concat().as_ptr(), fsa as *mut *mut T as *mut *mut u8)
             }
         }
     }
 }
-
-pub fn mount(mount_name: &str, accessor: &mut FsAccessor) -> Result<(), std::io::Error>{
-    if fs::detail::is_mount_available(mount_name) {
-        if fs::fsa::register(mount_name, accessor) != 0 {
-            Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to mount the filesystem accessor"))
-        } else {
-            Ok(())
-        }
-    } else {
-        Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, "The mount point provided is unavailable"))
-    }
-}
\ No newline at end of file
@@ -0,0 +1,73 @@
+use std::fmt;
+
+use crate::{ fs, FileSystemAccessor, FsAccessor };
+
+/// Reasons `mount` can fail.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MountError {
+    /// The mount name is invalid or already in use
+    Unavailable,
+    /// `nn::fs::fsa::Register` rejected the filesystem with this result
+    RegistrationFailed(u32),
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MountError::Unavailable => write!(f, "The mount point provided is unavailable"),
+            MountError::RegistrationFailed(result) => write!(f, "Failed to mount the filesystem accessor ({:#x})", result),
+        }
+    }
+}
+
+impl std::error::Error for MountError {}
+
+/// A mounted filesystem, unmounted when the handle is dropped.
+///
+/// Use `MountHandle::forget` to keep the filesystem mounted for the rest of the process.
+#[must_use = "the filesystem is unmounted as soon as the handle is dropped"]
+pub struct MountHandle {
+    name: String,
+}
+
+impl MountHandle {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn unmount(self) {
+        drop(self)
+    }
+
+    /// Give up the handle without unmounting the filesystem.
+    pub fn forget(self) {
+        std::mem::forget(self)
+    }
+}
+
+impl Drop for MountHandle {
+    fn drop(&mut self) {
+        fs::unmount(&self.name);
+    }
+}
+
+/// Mount `accessor` under `mount_name`, making it reachable through `"mount_name:/path"`.
+pub fn mount<A: FileSystemAccessor + 'static>(mount_name: &str, accessor: A) -> Result<MountHandle, MountError> {
+    if !fs::detail::is_mount_available(mount_name) {
+        return Err(MountError::Unavailable);
+    }
+
+    let mut fsa = FsAccessor::new(accessor);
+
+    match fs::fsa::register(mount_name, &mut fsa) {
+        0 => Ok(MountHandle { name: mount_name.to_owned() }),
+        result => {
+            // Register only takes ownership on success
+            if !fsa.is_null() {
+                unsafe { ((*fsa).vtable.deleter)(&mut *fsa) };
+            }
+
+            Err(MountError::RegistrationFailed(result))
+        }
+    }
+}
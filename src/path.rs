@@ -0,0 +1,197 @@
+use std::ffi::CStr;
+use std::fmt;
+use std::path::Path;
+
+use crate::AccessorResult;
+
+/// Longest path the SDK accepts, excluding the null terminator.
+pub const PATH_LENGTH_MAX: usize = 0x300;
+
+/// A normalized path inside a mounted filesystem, as handed to `FileSystemAccessor` methods.
+///
+/// Both `/` and `\` are accepted as separators, `.` and `..` are resolved and the leading slash is dropped, so the root of the mount is the empty path and `"/dir/./file"` becomes `"dir/file"`.
+/// Paths that would escape above the root or exceed `PATH_LENGTH_MAX` are rejected with `AccessorResult::PathNotFound`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct NnPath(String);
+
+impl NnPath {
+    pub fn root() -> Self {
+        NnPath(String::new())
+    }
+
+    pub fn new<S: AsRef<str>>(path: S) -> Result<Self, AccessorResult> {
+        let path = path.as_ref();
+
+        if path.len() > PATH_LENGTH_MAX {
+            return Err(AccessorResult::PathNotFound);
+        }
+
+        if path.contains('\0') {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        let mut components: Vec<&str> = Vec::new();
+
+        for component in path.split(|c| c == '/' || c == '\\') {
+            match component {
+                "" | "." => (),
+                ".." => {
+                    components.pop().ok_or(AccessorResult::PathNotFound)?;
+                },
+                component => components.push(component),
+            }
+        }
+
+        Ok(NnPath(components.join("/")))
+    }
+
+    /// Read a null-terminated path handed over by the SDK.
+    ///
+    /// # Safety
+    /// `path` must be null or point to a null-terminated string.
+    pub unsafe fn from_ptr(path: *const u8) -> Result<Self, AccessorResult> {
+        if path.is_null() {
+            return Err(AccessorResult::Unexpected);
+        }
+
+        match CStr::from_ptr(path as _).to_str() {
+            Ok(path) => Self::new(path),
+            Err(_) => Err(AccessorResult::Unexpected),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    pub fn as_path(&self) -> &Path {
+        Path::new(&self.0)
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn components(&self) -> impl Iterator<Item = &str> {
+        self.0.split('/').filter(|component| !component.is_empty())
+    }
+
+    /// The last component of the path, or `None` for the root.
+    pub fn file_name(&self) -> Option<&str> {
+        self.components().last()
+    }
+
+    /// The path without its last component, or `None` for the root.
+    pub fn parent(&self) -> Option<NnPath> {
+        if self.is_root() {
+            None
+        } else {
+            Some(NnPath(self.0.rfind('/').map(|idx| self.0[..idx].to_owned()).unwrap_or_default()))
+        }
+    }
+
+    /// The path followed by each of its ancestors, ending with the root.
+    pub fn ancestors(&self) -> impl Iterator<Item = NnPath> {
+        std::iter::successors(Some(self.clone()), |path| path.parent())
+    }
+
+    /// Append the relative `path` to this one and normalize the result.
+    ///
+    /// `path` usually comes from a directory listing, so `.` and `..` components are rejected with `AccessorResult::PathNotFound` rather than resolved, which keeps the result under this path.
+    pub fn join<S: AsRef<str>>(&self, path: S) -> Result<NnPath, AccessorResult> {
+        let path = path.as_ref();
+
+        if path.split(|c| c == '/' || c == '\\').any(|component| component == "." || component == "..") {
+            return Err(AccessorResult::PathNotFound);
+        }
+
+        Self::new([self.0.as_str(), path].join("/"))
+    }
+
+    /// Whether `base` is this path or one of its ancestors.
+    pub fn starts_with(&self, base: &NnPath) -> bool {
+        base.is_root() || self.0 == base.0 || (self.0.starts_with(&base.0) && self.0.as_bytes()[base.0.len()] == b'/')
+    }
+
+    /// This path relative to `base`, if `base` is this path or one of its ancestors.
+    pub fn strip_prefix(&self, base: &NnPath) -> Option<NnPath> {
+        if !self.starts_with(base) {
+            None
+        } else if base.is_root() {
+            Some(self.clone())
+        } else {
+            Some(NnPath(self.0[base.0.len()..].trim_start_matches('/').to_owned()))
+        }
+    }
+}
+
+impl AsRef<Path> for NnPath {
+    fn as_ref(&self) -> &Path {
+        self.as_path()
+    }
+}
+
+impl AsRef<str> for NnPath {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for NnPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "/{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_separators_and_dots() {
+        assert_eq!(NnPath::new("/dir/./file").unwrap().as_str(), "dir/file");
+        assert_eq!(NnPath::new("dir\\sub//file").unwrap().as_str(), "dir/sub/file");
+        assert_eq!(NnPath::new("/dir/sub/../file").unwrap().as_str(), "dir/file");
+        assert!(NnPath::new("/").unwrap().is_root());
+        assert!(NnPath::new("").unwrap().is_root());
+    }
+
+    #[test]
+    fn rejects_escaping_the_root() {
+        assert_eq!(NnPath::new("/.."), Err(AccessorResult::PathNotFound));
+        assert_eq!(NnPath::new("dir/../.."), Err(AccessorResult::PathNotFound));
+        assert_eq!(NnPath::new("dir\0file"), Err(AccessorResult::Unexpected));
+    }
+
+    #[test]
+    fn enforces_the_length_limit() {
+        let longest = "a".repeat(PATH_LENGTH_MAX);
+
+        assert_eq!(NnPath::new(&longest).unwrap().as_str(), longest);
+        assert_eq!(NnPath::new(["/", &longest].concat()), Err(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn join_rejects_dot_components() {
+        let dir = NnPath::new("dir").unwrap();
+
+        assert_eq!(dir.join("file").unwrap().as_str(), "dir/file");
+        assert_eq!(dir.join("sub/file").unwrap().as_str(), "dir/sub/file");
+        assert_eq!(dir.join(".."), Err(AccessorResult::PathNotFound));
+        assert_eq!(dir.join("."), Err(AccessorResult::PathNotFound));
+        assert_eq!(dir.join("sub\\..\\.."), Err(AccessorResult::PathNotFound));
+    }
+
+    #[test]
+    fn ancestry() {
+        let path = NnPath::new("dir/sub/file").unwrap();
+        let dir = NnPath::new("dir").unwrap();
+
+        assert_eq!(path.file_name(), Some("file"));
+        assert_eq!(path.parent().unwrap().as_str(), "dir/sub");
+        assert_eq!(path.ancestors().count(), 4);
+        assert!(path.starts_with(&dir));
+        assert!(!NnPath::new("directory").unwrap().starts_with(&dir));
+        assert_eq!(path.strip_prefix(&dir).unwrap().as_str(), "sub/file");
+    }
+}